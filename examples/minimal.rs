@@ -1,7 +1,6 @@
 extern crate hubbub;
 
 use hubbub::prelude::*;
-use std::process::exit;
 
 struct App {}
 
@@ -15,18 +14,19 @@ async fn main() -> Result<()> {
                     println!("Bot ready!");
 
                     let mut ctx = ctx.lock().await;
-                    println!("Logged in as {}", ctx.user.as_ref().unwrap().username);
+                    let user = ctx.user.as_ref().ok_or(Error::NotLoggedIn)?;
+                    println!("Logged in as {}", user.username);
 
-                    let resp = ctx
-                        .request(Method::GET, "/v9/users/@me", None)
-                        .await
-                        .expect("Couldn't send API request");
+                    // Errors returned here go to `Client::on_error`
+                    let resp = ctx.request(Method::GET, "/v9/users/@me", None).await?;
                     println!("{resp:#?}");
 
-                    println!("\nBio:\n{}", resp.body["bio"].as_str().unwrap());
+                    let bio = resp.body["bio"].as_str().ok_or_else(|| anyhow::anyhow!("No bio in {resp:?}"))?;
+                    println!("\nBio:\n{bio}");
 
-                    exit(0);
+                    ctx.shutdown.shutdown();
                 }
+                anyhow::Ok(())
             },
         ),
     )
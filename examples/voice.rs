@@ -0,0 +1,75 @@
+extern crate hubbub;
+
+use hubbub::prelude::*;
+
+struct App {
+    guild_id: Snowflake,
+    channel_id: Snowflake,
+    frames: Vec<Vec<u8>>,
+}
+
+// Reads opus frames stored as a little-endian u16 length followed by the frame, back to back
+fn read_frames(path: &str) -> Result<Vec<Vec<u8>>> {
+    let data = std::fs::read(path)?;
+    let mut frames = vec![];
+
+    let mut rest = data.as_slice();
+    while rest.len() >= 2 {
+        let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        let frame = rest.get(2..2 + len).ok_or(anyhow::anyhow!("Truncated frame"))?;
+        frames.push(frame.to_vec());
+        rest = &rest[2 + len..];
+    }
+
+    Ok(frames)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let env = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("Make sure to set the \"{name}\" env variable"));
+
+    let app = App {
+        guild_id: env("GUILD_ID").parse()?,
+        channel_id: env("CHANNEL_ID").parse()?,
+        frames: read_frames(&env("FRAMES"))?,
+    };
+
+    let mut client = Client::new(
+        app,
+        Box::from(
+            |ctx: Ctx, ws: Ws, model: Model<App>, msg: DiscordMessage| async move {
+                if msg.event.as_deref() != Some("READY") {
+                    return anyhow::Ok(());
+                }
+
+                // The client keeps reading while we wait, so the join can complete from here
+                let mut model = model.lock().await;
+
+                let join = ws.lock().await
+                    .update_voice_state(ctx.clone(), model.guild_id, Some(model.channel_id), false, false, false)
+                    .await?;
+                let info = join.await?.ok_or(anyhow::anyhow!("Not in a voice channel"))?;
+
+                let mut voice = VoiceConnection::connect(info).await?;
+                voice.play(std::mem::take(&mut model.frames)).await?;
+                voice.close().await?;
+
+                let leave = ws.lock().await
+                    .update_voice_state(ctx.clone(), model.guild_id, None, false, false, false)
+                    .await?;
+                leave.await?;
+
+                ctx.lock().await.shutdown.shutdown();
+                Ok(())
+            },
+        ),
+    )
+    .await?;
+
+    let token = env("TOKEN");
+    client.token(token).await.expect("Couldn't set token");
+    client.login().await.expect("Error while logging in");
+    client.run().await.expect("Error while running");
+
+    Ok(())
+}
@@ -1,36 +1,462 @@
 use std::future::Future;
-use crate::prelude::{Ctx, Model, Ws};
+use crate::prelude::{Model, Ws};
 use anyhow::Result;
-use crate::context::{Context, ResumeInfo};
+use crate::context::{Context, Ctx, ResumeInfo, Session, SessionStore};
+use crate::types::{
+    activity::Activity,
+    channel::Channel,
+    events::{Event, MessageCreate, MessageDelete, MessageReactionAdd},
+    message::{Message, PartialMessage},
+    guild::PartialGuildMember,
+    member_list::GuildMemberListUpdate,
+    notifications::UserGuildSettings,
+    presence::{GatewayPresence, Presence, StatusType},
+    settings::{self, UserSettingsProtoUpdate},
+    timestamp::Timestamp,
+    voice::VoiceState,
+    Snowflake,
+};
 use crate::error::Error;
-use std::{cmp::max, collections::VecDeque, sync::Arc, time::Duration};
-use tokio::sync::Mutex;
-use crate::types::gateway::Ready;
-use crate::websocket::{DiscordMessage, Websocket};
+use std::{
+    any::Any,
+    cmp::max,
+    collections::{HashMap, VecDeque},
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use crate::types::gateway::{ChannelDelete, GuildRoleDelete, GuildRoleUpdate, Ready, ReadySupplemental};
+use crate::websocket::{DiscordMessage, Lifecycle, Reader, ReconnectPolicy, Websocket};
+use futures_util::future::{join_all, FutureExt, Shared};
+use serde::de::DeserializeOwned;
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
+use tokio::task::{JoinError, JoinSet};
 pub type Handler<F, M> = dyn Fn(Ctx, Ws, Model<M>, DiscordMessage) -> F + Send;
 
+/// Gets errors the client recovered from, with the dispatch event they came from (if any)
+pub type ErrorHook = dyn Fn(&anyhow::Error, Option<&str>, Ctx) + Send + Sync;
+
+/// What a handler can return: nothing, or a `Result` whose error goes to `Client::on_error`
+pub trait HandlerResult {
+    fn into_result(self) -> Result<()>;
+}
+
+impl HandlerResult for () {
+    fn into_result(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: Into<anyhow::Error>> HandlerResult for std::result::Result<(), E> {
+    fn into_result(self) -> Result<()> {
+        self.map_err(Into::into)
+    }
+}
+
+/// How `Client::run` calls the handler
+///
+/// With `Inline`, one handler runs at a time in the order events arrive. With `Concurrent`,
+/// every handler call is its own task and at most `limit` run at once; the event loop waits
+/// for a free slot. Events still run in order per channel, after the guild-wide events
+/// (a `guild_id` but no `channel_id`) that came before them, and guild-wide events wait for
+/// everything before them in their guild. The cache is always updated before the handler
+/// is started, and READY and RESUMED wait for every running handler and run on their own
+/// before anything after them.
+///
+/// Either way, the client keeps reading while it waits on handlers: new dispatches go to
+/// `Listeners` right away and are queued for their own handler, so a handler can await
+/// `wait_for`, `request_guild_members` and the like.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DispatchMode {
+    #[default]
+    Inline,
+    Concurrent { limit: usize },
+}
+
+/// Cloneable handle for stopping a running [`Client`]
+///
+/// Every [`Context`] carries one (`ctx.shutdown`), so handlers can stop the client too.
+/// Once triggered, `Client::run` lets the current handler finish, closes the websocket,
+/// stops heartbeating and returns `Ok(())`.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        log::debug!("Shutdown requested");
+        self.0.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once [`ShutdownHandle::shutdown`] has been called on any clone
+    pub async fn wait(&self) {
+        let mut rx = self.0.subscribe();
+        let _ = rx.wait_for(|v| *v).await;
+    }
+}
+
+type Filter = Box<dyn Fn(&DiscordMessage) -> bool + Send + Sync>;
+type Listener = (Filter, mpsc::UnboundedSender<DiscordMessage>);
+
+/// Lets code outside the event loop wait for dispatch events
+///
+/// Every dispatch the client receives is offered to each listener, matching ones get a copy.
+/// A listener goes away once its receiver is dropped.
+#[derive(Clone, Default)]
+pub struct Listeners(Arc<std::sync::Mutex<Vec<Listener>>>);
+
+impl std::fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Listeners({})", self.lock().len())
+    }
+}
+
+impl Listeners {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Listener>> {
+        // Filters can't leave the list in a broken state, so a poisoned lock is fine to keep using
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn listen(
+        &self,
+        filter: impl Fn(&DiscordMessage) -> bool + Send + Sync + 'static,
+    ) -> mpsc::UnboundedReceiver<DiscordMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.lock().push((Box::new(filter), tx));
+        rx
+    }
+
+    /// Resolves with the next `E` that matches `pred`, or fails with `Error::Timeout`
+    ///
+    /// Starts listening right away, so an event that arrives before the future is awaited is
+    /// still seen. Events come in while `Client::run` is running, including while it waits on
+    /// the handler that awaits this, in either `DispatchMode`.
+    pub fn wait_for<E: Event>(
+        &self,
+        pred: impl Fn(&E) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<E>> + Send {
+        let mut events = self.listen(|msg| msg.event.as_deref() == Some(E::NAME));
+
+        async move {
+            let wait = async move {
+                while let Some(msg) = events.recv().await {
+                    match serde_json::from_value::<E>(msg.data) {
+                        Ok(event) if pred(&event) => return Some(event),
+                        Ok(_) => (),
+                        Err(e) => log::debug!("Skipping {} that couldn't be decoded: {e}", E::NAME),
+                    }
+                }
+                None
+            };
+
+            match tokio::time::timeout(timeout, wait).await {
+                Ok(Some(event)) => Ok(event),
+                _ => Err(Error::Timeout(timeout).into()),
+            }
+        }
+    }
+
+    /// Every reaction added to `message` within `duration`, see `wait_for`
+    pub fn collect_reactions(
+        &self,
+        message: Snowflake,
+        duration: Duration,
+    ) -> impl Future<Output = Vec<MessageReactionAdd>> + Send {
+        self.collect(move |r: &MessageReactionAdd| r.message_id == message, duration, usize::MAX)
+    }
+
+    /// Up to `max` messages in `channel` that match `filter`, collected for at most `duration`,
+    /// see `wait_for`
+    pub fn collect_messages(
+        &self,
+        channel: Snowflake,
+        filter: impl Fn(&MessageCreate) -> bool + Send + 'static,
+        max: usize,
+        duration: Duration,
+    ) -> impl Future<Output = Vec<MessageCreate>> + Send {
+        self.collect(move |m: &MessageCreate| m.channel_id == channel && filter(m), duration, max)
+    }
+
+    // Stops after `max` events or once `duration` is up, whichever comes first
+    fn collect<E: Event>(
+        &self,
+        pred: impl Fn(&E) -> bool + Send + 'static,
+        duration: Duration,
+        max: usize,
+    ) -> impl Future<Output = Vec<E>> + Send {
+        let mut events = self.listen(|msg| msg.event.as_deref() == Some(E::NAME));
+
+        async move {
+            let mut collected = Vec::new();
+
+            let into = &mut collected;
+            let collect = async move {
+                while into.len() < max {
+                    let Some(msg) = events.recv().await else { break };
+                    match serde_json::from_value::<E>(msg.data) {
+                        Ok(event) if pred(&event) => into.push(event),
+                        Ok(_) => (),
+                        Err(e) => log::debug!("Skipping {} that couldn't be decoded: {e}", E::NAME),
+                    }
+                }
+            };
+
+            let _ = tokio::time::timeout(duration, collect).await;
+
+            collected
+        }
+    }
+
+    pub fn feed(&self, msg: &DiscordMessage) {
+        let mut listeners = self.lock();
+        listeners.retain(|(_, tx)| !tx.is_closed());
+
+        for (filter, tx) in listeners.iter() {
+            if filter(msg) {
+                let _ = tx.send(msg.clone());
+            }
+        }
+    }
+}
+
+/// `Listeners` helpers on the shared context, so handlers can write `ctx.wait_for(...)`
+pub trait Collectors {
+    fn wait_for<E: Event>(
+        &self,
+        pred: impl Fn(&E) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<E>> + Send;
+
+    fn collect_reactions(
+        &self,
+        message: Snowflake,
+        duration: Duration,
+    ) -> impl Future<Output = Vec<MessageReactionAdd>> + Send;
+
+    fn collect_messages(
+        &self,
+        channel: Snowflake,
+        filter: impl Fn(&MessageCreate) -> bool + Send + 'static,
+        max: usize,
+        duration: Duration,
+    ) -> impl Future<Output = Vec<MessageCreate>> + Send;
+}
+
+// Listening starts when these are called, not when they're first polled
+impl Collectors for Ctx {
+    fn wait_for<E: Event>(
+        &self,
+        pred: impl Fn(&E) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<E>> + Send {
+        self.listeners().wait_for(pred, timeout)
+    }
+
+    fn collect_reactions(
+        &self,
+        message: Snowflake,
+        duration: Duration,
+    ) -> impl Future<Output = Vec<MessageReactionAdd>> + Send {
+        self.listeners().collect_reactions(message, duration)
+    }
+
+    fn collect_messages(
+        &self,
+        channel: Snowflake,
+        filter: impl Fn(&MessageCreate) -> bool + Send + 'static,
+        max: usize,
+        duration: Duration,
+    ) -> impl Future<Output = Vec<MessageCreate>> + Send {
+        self.listeners().collect_messages(channel, filter, max, duration)
+    }
+}
+
+/// Builds a [`Client`], see `Client::builder`
+pub struct ClientBuilder<F, Model>
+where
+    F: Future + Send + 'static,
+    F::Output: HandlerResult + Send + 'static,
+{
+    model: Model,
+    handler: Box<Handler<F, Model>>,
+    policy: ReconnectPolicy,
+}
+
+impl<F, Model> ClientBuilder<F, Model>
+where
+    F: Future + Send + 'static,
+    F::Output: HandlerResult + Send + 'static, Model: Send
+{
+    /// Used for the first connection and every reconnect after it
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Connects to the gateway, retrying according to the reconnect policy
+    pub async fn build(self) -> Result<Client<F, Model>> {
+        let ctx = Ctx::new(Context::default());
+        let shutdown = ctx.lock().await.shutdown.clone();
+        let listeners = ctx.listeners().clone();
+        let ws = self.policy.retry(async |_| Websocket::new().await).await?;
+
+        Ok(Client {
+            ws: Arc::new(Mutex::from(ws)),
+            ctx,
+            model: Arc::new(Mutex::from(self.model)),
+            handler: self.handler,
+            shutdown,
+            listeners,
+            policy: self.policy,
+            store: None,
+            resume_window: Duration::from_secs(5 * 60),
+            on_error: Arc::new(|err, event, _| log::warn!("{}: {err:#}", event.unwrap_or("gateway"))),
+            catch_panics: false,
+            mode: DispatchMode::Inline,
+            permits: Arc::new(Semaphore::new(0)),
+            tasks: JoinSet::new(),
+            ordering: Ordering::default(),
+            panicked: None,
+            reader: None,
+            queued: VecDeque::new(),
+        })
+    }
+}
+
 pub struct Client<F, Model>
 where
     F: Future + Send + 'static,
-    F::Output: Send + 'static,
+    F::Output: HandlerResult + Send + 'static,
 {
+    // When both are needed, `ctx` is locked first. Nothing locks `ctx` while holding `ws`
     ws: Arc<Mutex<Websocket>>,
-    ctx: Arc<Mutex<Context>>,
+    ctx: Ctx,
     handler: Box<Handler<F, Model>>,
     model: Arc<Mutex<Model>>,
+    shutdown: ShutdownHandle,
+    listeners: Listeners,
+    policy: ReconnectPolicy,
+    store: Option<Box<dyn SessionStore>>,
+    resume_window: Duration,
+    on_error: Arc<ErrorHook>,
+    catch_panics: bool,
+    mode: DispatchMode,
+    permits: Arc<Semaphore>,
+    tasks: JoinSet<()>,
+    ordering: Ordering,
+    panicked: Option<String>, // first handler panic when they aren't caught, stops `run`
+    reader: Option<Reader>,
+    queued: VecDeque<DiscordMessage>, // read while waiting on a handler, not dispatched yet
 }
 
 impl<F, Model> Client<F, Model>
 where
     F: Future + Send + 'static,
-    F::Output: Send + 'static, Model: Send
+    F::Output: HandlerResult + Send + 'static, Model: Send
 {
     pub async fn new(model: Model, handler: Box<Handler<F, Model>>) -> Result<Self> {
-        Ok(Self {
-            ws: Arc::new(Mutex::from(Websocket::new().await?)),
-            ctx: Arc::new(Mutex::from(Context::default())),
-            model: Arc::new(Mutex::from(model)),
+        Self::builder(model, handler).build().await
+    }
+
+    /// For settings that have to be in place before the client connects
+    pub fn builder(model: Model, handler: Box<Handler<F, Model>>) -> ClientBuilder<F, Model> {
+        ClientBuilder {
+            model,
             handler,
+            policy: ReconnectPolicy::default(),
+        }
+    }
+
+    /// Saves the session into `store` and resumes from it on the next login
+    ///
+    /// The session is saved after READY and RESUMED, whenever the gateway acknowledges a
+    /// heartbeat and on shutdown, so it is at most one heartbeat behind if the process dies.
+    /// Resuming doesn't replay READY: after resuming a saved session the cache starts out empty
+    /// apart from `ctx.user`, and only fills up with what later events bring in. Fetch anything
+    /// else you need (guilds, members, ...) over the http api.
+    pub fn session_store(&mut self, store: impl SessionStore + 'static) {
+        self.store = Some(Box::new(store));
+    }
+
+    /// Saved sessions older than this are ignored, defaults to 5 minutes
+    pub fn resume_window(&mut self, window: Duration) {
+        self.resume_window = window;
+    }
+
+    /// Only used for reconnecting, set it on the builder to cover the first connection too
+    pub fn reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy;
+    }
+
+    /// Checks permissions against the cache before REST calls, see `Context::preflight`
+    pub async fn permission_preflight(&mut self, enabled: bool) {
+        self.ctx.lock().await.preflight = enabled;
+    }
+
+    /// Replaces the default hook, which logs a warning
+    ///
+    /// Errors returned by the handler end up here along with the event it was handling.
+    /// Malformed frames and events that can't be decoded are reported here and skipped,
+    /// a broken connection is reported and then resumed according to the reconnect policy.
+    pub fn on_error(&mut self, hook: impl Fn(&anyhow::Error, Option<&str>, Ctx) + Send + Sync + 'static) {
+        self.on_error = Arc::new(hook);
+    }
+
+    /// Turns handler panics into `Error::HandlerPanicked` for the error hook, off by default
+    ///
+    /// Otherwise the first handler that panics stops `Client::run`, which shuts down like it
+    /// would for `ShutdownHandle` and then returns `Error::HandlerPanicked`.
+    pub fn catch_panics(&mut self, enabled: bool) {
+        self.catch_panics = enabled;
+    }
+
+    pub fn dispatch_mode(&mut self, mode: DispatchMode) {
+        if let DispatchMode::Concurrent { limit } = mode {
+            self.permits = Arc::new(Semaphore::new(limit.max(1)));
+        }
+        self.mode = mode;
+    }
+
+    fn report(&self, err: anyhow::Error, event: Option<&str>) {
+        (self.on_error)(&err, event, self.ctx.clone());
+    }
+
+    // Decode errors are reported, the event still reaches the handler as raw json
+    fn decode<T: DeserializeOwned>(&self, msg: &DiscordMessage) -> Option<T> {
+        match serde_json::from_value(msg.data.clone()) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.report(Error::MalformedPayload(e.to_string()).into(), msg.event.as_deref());
+                None
+            }
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Everything needed to resume the current gateway session, e.g. after a shutdown
+    pub async fn session(&self) -> Option<Session> {
+        let ctx = self.ctx.lock().await;
+        let ws = self.ws.lock().await;
+
+        Some(Session {
+            resume_info: ctx.resume_info.clone()?,
+            sequence: ws.sequence?,
+            saved_at: Timestamp(chrono::Utc::now().fixed_offset()),
+            user: ctx.user.clone(),
         })
     }
 
@@ -41,13 +467,11 @@ where
         match ctx.request(http::Method::GET, "/v9/users/@me", None).await {
             Ok(r) => {
                 // If return value was an error
-                if r.body.get("code").is_some() && r.body.get("message").is_some() {
-                    Err(Error::InvalidToken(
-                        r.body.get("message").unwrap().as_str().unwrap().to_string(),
-                    )
-                        .into())
-                } else {
-                    Ok(())
+                match (r.body.get("code"), r.body.get("message")) {
+                    (Some(_), Some(message)) => {
+                        Err(Error::InvalidToken(message.as_str().unwrap_or_default().to_string()).into())
+                    }
+                    _ => Ok(()),
                 }
             }
             Err(e) => Err(e.context("Failed validating token")),
@@ -55,29 +479,81 @@ where
     }
 
     pub async fn login(&mut self) -> Result<()> {
-        let ctx = self.ctx.lock().await;
+        if let Some(session) = self.load_session() {
+            log::debug!("Trying to resume saved session {}", session.resume_info.id);
+
+            {
+                let mut ctx = self.ctx.lock().await;
+                ctx.resume_info = Some(session.resume_info);
+                ctx.user = session.user;
+                self.ws.lock().await.sequence = Some(session.sequence);
+            }
 
-        if let Some(ref token) = ctx.auth {
-            self.ws.lock().await.login(token).await?;
-        } else {
-            return Err(anyhow::anyhow!(Error::NoTokenGiven));
+            // If discord doesn't like it, the INVALID_SESSION handler identifies on the same connection
+            match self.resume().await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    log::warn!("Couldn't resume saved session: {e}");
+                    self.ctx.lock().await.resume_info = None;
+                    *self.ws.lock().await = Websocket::new().await?;
+                }
+            }
         }
+
+        self.identify().await
+    }
+
+    /// Presence to come online with, sent as part of identify
+    pub async fn initial_presence(&mut self, presence: GatewayPresence) -> Result<()> {
+        presence.validate()?;
+        self.ctx.lock().await.presence = Some(presence);
         Ok(())
     }
 
+    /// Updates presence on the gateway and remembers it for when we have to identify again
+    ///
+    /// Before `login` there is no session to update yet, so it is only stored and sent with identify.
+    pub async fn set_presence(&mut self, status: StatusType, activities: Vec<Activity>) -> Result<()> {
+        let presence = GatewayPresence::new(status, activities);
+        presence.validate()?;
+
+        let mut ctx = self.ctx.lock().await;
+        let mut ws = self.ws.lock().await;
+        if ws.ready {
+            return ws.update_presence(&mut ctx, presence).await;
+        }
+
+        ctx.presence = Some(presence);
+        Ok(())
+    }
+
+    // Logging in waits on the network, so the context isn't held while doing it
+    async fn identify(&mut self) -> Result<()> {
+        let (token, presence) = {
+            let ctx = self.ctx.lock().await;
+            (ctx.auth.clone().ok_or(Error::NoTokenGiven)?, ctx.presence.clone())
+        };
+
+        self.ws.lock().await.login_with(&token, presence.as_ref()).await
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         let ws = self.ws.lock().await;
 
         let ws_ref = self.ws.clone();
         let hb = ws.heartbeat;
-        tokio::task::spawn(async move {
+        let shutdown = self.shutdown.clone();
+        let heartbeat = tokio::task::spawn(async move {
             let mut i = async_timer::Interval::platform_new(Duration::from_millis(hb));
 
             loop {
-                i.as_mut().await;
+                tokio::select! {
+                    _ = i.as_mut() => (),
+                    _ = shutdown.wait() => break,
+                }
 
                 let mut lock = ws_ref.lock().await;
-                let seq = lock.sequence.clone();
+                let seq = lock.sequence;
                 match lock.send(DiscordMessage::new_heartbeat(seq)).await {
                     Err(e) => log::warn!("Couldn't send heartbeat: {e}"),
                     Ok(_) => log::trace!("Sent heartbeat successfully: {seq:?}"),
@@ -85,109 +561,487 @@ where
             }
         });
 
+        // Reading goes through its own handle so the websocket stays unlocked while we wait
+        self.reader = Some(ws.reader());
         drop(ws);
 
-        let mut dispatch_queue: VecDeque<DiscordMessage> = VecDeque::with_capacity(8);
-        'main: loop {
-            let mut ws = match self.ws.try_lock() {
-                Ok(ws) => ws,
-                Err(_) => {
-                    tokio::time::sleep(Duration::from_millis(2)).await;
-                    continue;
-                }
+        // Set when reconnecting gave up, `run` still cleans up before returning it
+        let mut failed = None;
+
+        loop {
+            self.reap();
+            if self.panicked.is_some() || self.shutdown.is_shutdown() {
+                break;
+            }
+
+            let msg = match self.queued.pop_front() {
+                Some(msg) => msg,
+                None => tokio::select! {
+                    msg = self.receive() => match msg {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
+                    _ = self.shutdown.wait() => break,
+                },
             };
 
-            let mut seq = ws.sequence;
-            while let Some(msg) = ws.try_read().await? {
-                match msg.op {
-                    0 => {
-                        log::trace!(
-                            "Got dispatch event #{}: {}",
-                            msg.seq.as_ref().unwrap(),
-                            msg.event.as_ref().unwrap()
-                        );
-                        seq = max(seq, msg.seq);
-                        dispatch_queue.push_back(msg)
+            let handled = match msg.op {
+                0 => {
+                    let mut ws = self.read_while(self.ws.clone().lock_owned()).await;
+                    ws.sequence = max(ws.sequence, msg.seq);
+                    drop(ws);
+
+                    self.dispatch(msg).await;
+                    Ok(())
+                }
+                1 => {
+                    log::debug!("Gateway asked for heartbeat");
+                    let mut ws = self.ws.lock().await;
+                    let seq = ws.sequence;
+                    let sent = ws.send(DiscordMessage::new_heartbeat(seq)).await;
+                    drop(ws);
+
+                    match sent {
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            self.report(e, None);
+                            self.reconnect().await
+                        }
                     }
-                    1 => {
-                        log::debug!("Gateway asked for heartbeat");
-                        let seq = ws.sequence.clone();
-                        ws.send(DiscordMessage::new_heartbeat(seq)).await?;
+                }
+                7 => {
+                    log::debug!("Gateway asked for reconnect");
+                    self.reconnect().await
+                }
+                9 => { // Invalid session
+                    log::debug!("Gateway sent 'Invalid session'");
+
+                    if msg.data.as_bool().unwrap_or(false) {
+                        log::debug!("Trying to resume");
+                        self.reconnect().await
+                    } else {
+                        log::debug!("Session can't be resumed, identifying again");
+                        match self.identify_again().await {
+                            Ok(_) => Ok(()),
+                            Err(e) => {
+                                self.report(e, None);
+                                self.reconnect().await
+                            }
+                        }
                     }
-                    7 => {
-                        log::debug!("Gateway asked for reconnect");
-                        drop(ws);
-                        self.resume().await?;
-                        continue 'main;
+                }
+                11 => {
+                    log::trace!("Gateway acknowledged heartbeat");
+                    self.save_session().await;
+                    Ok(())
+                }
+                255 => {
+                    log::debug!("Websocket disconnected... trying to resume");
+                    self.reconnect().await
+                }
+                _ => Ok(()),
+            };
+
+            if let Err(e) = handled {
+                failed = Some(e);
+                break;
+            }
+        }
+
+        log::debug!("Shutting down");
+        self.shutdown.shutdown(); // also stops the heartbeat if we're stopping on our own
+        self.drain().await;
+        let _ = heartbeat.await;
+        let closed = self.ws.lock().await.close().await;
+
+        self.save_session().await;
+
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        if let Some(panic) = self.panicked.take() {
+            return Err(Error::HandlerPanicked(panic).into());
+        }
+        closed
+    }
+
+    async fn save_session(&self) {
+        let Some(store) = &self.store else { return };
+        let Some(session) = self.session().await else { return };
+
+        log::trace!("Saving session {} at #{}", session.resume_info.id, session.sequence);
+        if let Err(e) = store.save(&session) {
+            self.report(e.context("Couldn't save session"), None);
+        }
+    }
+
+    fn load_session(&self) -> Option<Session> {
+        let session = match self.store.as_ref()?.load() {
+            Ok(s) => s?,
+            Err(e) => {
+                log::warn!("Couldn't load saved session: {e}");
+                return None;
+            }
+        };
+
+        if session.age() > self.resume_window {
+            log::debug!("Saved session is too old to resume");
+            return None;
+        }
+
+        Some(session)
+    }
+
+    // Reads the next frame and hands dispatches to the listeners right away, everything else
+    // (sequence number, cache, handler) waits until it's the frame's turn. Nothing is awaited
+    // after reading, so dropping this halfway never loses a frame.
+    async fn receive(&self) -> Option<DiscordMessage> {
+        let msg = match self.reader.as_ref()?.read().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.report(e, None);
+                return None;
+            }
+        };
+
+        if msg.op == 0 {
+            let (Some(seq), Some(event)) = (msg.seq, msg.event.as_deref()) else {
+                let err = Error::MalformedPayload("dispatch without a sequence number or event".to_string());
+                self.report(err.into(), msg.event.as_deref());
+                return None;
+            };
+            log::trace!("Got dispatch event #{seq}: {event}");
+
+            self.listeners.feed(&msg);
+        }
+
+        Some(msg)
+    }
+
+    // Waits for `fut` while frames keep being read, so a handler waiting on listeners still
+    // gets its events. What's read is queued for dispatch. Reading pauses at anything that
+    // isn't a dispatch or heartbeat ACK, as that may replace the connection.
+    async fn read_while<T>(&mut self, fut: impl Future<Output = T>) -> T {
+        tokio::pin!(fut);
+
+        loop {
+            let reading = self.reader.is_some() && self.queued.iter().all(|m| matches!(m.op, 0 | 11));
+
+            tokio::select! {
+                out = &mut fut => return out,
+                msg = self.receive(), if reading => {
+                    if let Some(msg) = msg {
+                        self.queued.push_back(msg);
                     }
-                    9 => { // Invalid session
-                        log::debug!("Gateway sent 'Invalid session'");
-
-                        if msg.data.as_bool().unwrap() {
-                            log::debug!("Trying to resume");
-                            drop(ws);
-                            self.resume().await?;
-                            continue 'main;
-                        } else {
-                            log::debug!("Reconnecting");
-                            drop(ws);
-                            self.login().await?;
-                            continue 'main;
-                        }
+                }
+            }
+        }
+    }
+
+    // A handler may hold the context while it waits for events, so keep reading meanwhile
+    async fn lock_ctx(&mut self) -> OwnedMutexGuard<Context> {
+        self.read_while(Arc::clone(&self.ctx).lock_owned()).await
+    }
+
+    // Connection events don't come from the socket, so they get to the listeners here
+    async fn dispatch_lifecycle(&mut self, lifecycle: Lifecycle) {
+        let msg = lifecycle.into();
+        self.listeners.feed(&msg);
+        self.dispatch(msg).await;
+    }
+
+    async fn dispatch(&mut self, msg: DiscordMessage) {
+        let Some(event) = msg.event.as_deref() else { return };
+
+        match event {
+            "RESUMED" => {
+                log::debug!("Resumed");
+                self.save_session().await;
+            }
+            "READY" => {
+                if let Some(ready) = self.decode::<Ready>(&msg) {
+                    let mut ctx = self.lock_ctx().await;
+                    ctx.user = Some(ready.user);
+                    ctx.resume_info = Some(ResumeInfo {
+                        url: ready.resume_gateway_url,
+                        id: ready.session_id,
+                    });
+
+                    // A fresh READY (e.g. after identifying again) replaces whatever we had
+                    ctx.cache.guilds = ready.cached_guilds;
+                    ctx.cache.users = ready.cached_users;
+                    ctx.cache.private_channels = ready.private_channels;
+                    ctx.cache.guild_settings = ready
+                        .user_guild_settings
+                        .map(|s| s.entries.into_iter().map(|e| (e.guild_id, e)).collect())
+                        .unwrap_or_default();
+
+                    let (preloaded, error) = match ready.user_settings_proto.as_deref().map(settings::decode) {
+                        Some(Ok(settings)) => (Some(settings), None),
+                        Some(Err(e)) => (None, Some(e)),
+                        None => (None, None),
+                    };
+                    ctx.settings.preloaded = preloaded;
+
+                    log::trace!("Context after READY: {ctx:?}");
+                    drop(ctx);
+
+                    if let Some(e) = error {
+                        self.report(e.context("Couldn't decode user settings"), Some(event));
+                    }
+                    self.save_session().await;
+                }
+            }
+            "READY_SUPPLEMENTAL" => {
+                if let Some(supplemental) = self.decode::<ReadySupplemental>(&msg) {
+                    let mut ctx = self.lock_ctx().await;
+                    let ctx = &mut *ctx;
+                    ctx.cache.apply_ready_supplemental(supplemental, ctx.user.as_ref());
+                }
+            }
+            "USER_SETTINGS_PROTO_UPDATE" => {
+                if let Some(update) = self.decode::<UserSettingsProtoUpdate>(&msg) {
+                    let applied = self.lock_ctx().await.settings.apply(&update);
+                    if let Err(e) = applied {
+                        self.report(e, Some(event));
                     }
-                    11 => {
-                        log::trace!("Gateway acknowledged heartbeat");
+                }
+            }
+            // Preflight checks against cached channels, overwrites and roles
+            "CHANNEL_CREATE" | "CHANNEL_UPDATE" | "THREAD_CREATE" | "THREAD_UPDATE" => {
+                if let Some(channel) = self.decode::<Channel>(&msg) {
+                    self.lock_ctx().await.cache.add_channel(channel);
+                }
+            }
+            "CHANNEL_DELETE" | "THREAD_DELETE" => {
+                if let Some(deleted) = self.decode::<ChannelDelete>(&msg) {
+                    self.lock_ctx().await.cache.remove_channel(deleted.guild_id, deleted.id);
+                }
+            }
+            "GUILD_ROLE_CREATE" | "GUILD_ROLE_UPDATE" => {
+                if let Some(update) = self.decode::<GuildRoleUpdate>(&msg) {
+                    self.lock_ctx().await.cache.add_role(update.guild_id, update.role);
+                }
+            }
+            "GUILD_ROLE_DELETE" => {
+                if let Some(deleted) = self.decode::<GuildRoleDelete>(&msg) {
+                    self.lock_ctx().await.cache.remove_role(deleted.guild_id, deleted.role_id);
+                }
+            }
+            "MESSAGE_CREATE" => {
+                if let Some(message) = self.decode::<Message>(&msg) {
+                    self.lock_ctx().await.cache.add_message(message);
+                }
+            }
+            "MESSAGE_UPDATE" => {
+                if let Some(partial) = self.decode::<PartialMessage>(&msg) {
+                    self.lock_ctx().await.cache.update_message(&partial);
+                }
+            }
+            "MESSAGE_DELETE" => {
+                if let Some(deleted) = self.decode::<MessageDelete>(&msg) {
+                    self.lock_ctx().await.cache.remove_message(deleted.channel_id, deleted.id);
+                }
+            }
+            "USER_GUILD_SETTINGS_UPDATE" => {
+                if let Some(settings) = self.decode::<UserGuildSettings>(&msg) {
+                    self.lock_ctx().await.cache.add_guild_settings(settings);
+                }
+            }
+            "PRESENCE_UPDATE" => {
+                if let Some(presence) = self.decode::<Presence>(&msg) {
+                    let mut ctx = self.lock_ctx().await;
+                    ctx.cache.update_user(&presence.user);
+                    ctx.cache.add_presence(presence);
+                }
+            }
+            "GUILD_MEMBER_UPDATE" => {
+                if let Some(member) = self.decode::<PartialGuildMember>(&msg) {
+                    match member.guild_id {
+                        Some(guild_id) => self.lock_ctx().await.cache.update_member(guild_id, &member),
+                        None => self.report(Error::MalformedPayload("no guild_id".to_string()).into(), Some(event)),
                     }
-                    255 => {
-                        log::debug!("Websocket disconnected... trying to resume");
-                        drop(ws);
-                        self.resume().await?;
-                        continue 'main;
+                }
+            }
+            "VOICE_STATE_UPDATE" => {
+                if let Some(state) = self.decode::<VoiceState>(&msg) {
+                    let mut ctx = self.lock_ctx().await;
+                    let ours = ctx.user.as_ref().is_some_and(|u| u.id == state.user_id);
+
+                    // However we left or got moved, the cached connection has to follow
+                    if let (true, Some(guild_id)) = (ours, state.guild_id) {
+                        match state.channel_id {
+                            None => {
+                                ctx.cache.voice.remove(&guild_id);
+                            }
+                            Some(channel_id) => {
+                                if let Some(info) = ctx.cache.voice.get_mut(&guild_id) {
+                                    info.channel_id = channel_id;
+                                }
+                            }
+                        }
                     }
-                    _ => (),
                 }
             }
-            ws.sequence = seq;
-            drop(ws);
+            "GUILD_MEMBER_LIST_UPDATE" => {
+                if let Some(update) = self.decode::<GuildMemberListUpdate>(&msg) {
+                    self.lock_ctx().await.cache.apply_member_list_update(update);
+                }
+            }
+            _ => (),
+        }
 
-            while !dispatch_queue.is_empty() {
-                if let Some(msg) = dispatch_queue.pop_front() {
-                    let event = msg.event.as_ref().unwrap();
+        let event = msg.event.clone();
+        let (channel, guild) = Ordering::keys(&msg);
+        let barrier = matches!(event.as_deref(), Some("READY" | "RESUMED"));
+        let handler = (self.handler)(self.ctx.clone(), self.ws.clone(), self.model.clone(), msg);
 
-                    if event.as_str() == "RESUMED" {
-                        log::debug!("Resumed");
-                    }
+        // Inline handlers are still their own task, so we can keep reading while they run
+        if self.mode == DispatchMode::Inline || barrier {
+            self.drain().await;
 
-                    if event.as_str() == "READY" {
-                        // this clone is DISGUSTING
-                        let mut ready: Ready =
-                            serde_json::from_value(msg.data.clone()).expect("Couldn't parse READY");
+            let task = tokio::spawn(finish(handler, self.catch_panics));
+            match self.read_while(task).await {
+                Ok(Err(e)) => self.report(e, event.as_deref()),
+                Err(e) => self.note_panic(e),
+                _ => (),
+            }
+            return;
+        }
 
-                        let mut ctx = self.ctx.lock().await;
-                        ctx.user = Some(ready.user);
-                        ctx.resume_info = Some(ResumeInfo {
-                            url: ready.resume_gateway_url,
-                            id: ready.session_id,
-                        });
+        // Waiting for a slot here is what keeps at most `limit` handlers running
+        let permit = self.read_while(self.permits.clone().acquire_owned()).await;
 
-                        ctx.cache.guilds.append(&mut ready.cached_guilds);
-                        ctx.cache.users.append(&mut ready.cached_users);
+        let (done, finished) = oneshot::channel();
+        let previous = self.ordering.enqueue(channel, guild, finished.shared());
 
-                        log::trace!("Context after READY: {ctx:?}");
-                    }
+        let catch_panics = self.catch_panics;
+        let on_error = self.on_error.clone();
+        let ctx = self.ctx.clone();
+
+        self.tasks.spawn(async move {
+            join_all(previous).await;
+
+            if let Err(e) = finish(handler, catch_panics).await {
+                on_error(&e, event.as_deref(), ctx);
+            }
+
+            drop((done, permit));
+        });
+    }
+
+    // Unless panics are caught, a handler that panicked stops `run` with an error
+    fn note_panic(&mut self, err: JoinError) {
+        if err.is_panic() && self.panicked.is_none() {
+            self.panicked = Some(panic_message(err.into_panic().as_ref()));
+        }
+    }
+
+    fn reap(&mut self) {
+        while let Some(result) = self.tasks.try_join_next() {
+            if let Err(e) = result {
+                self.note_panic(e);
+            }
+        }
+    }
 
-                    let ctx = self.ctx.clone();
-                    let ws = self.ws.clone();
-                    let model = self.model.clone();
+    // Waits for every running handler
+    async fn drain(&mut self) {
+        let mut tasks = std::mem::take(&mut self.tasks);
 
-                    (self.handler)(ctx, ws, model, msg).await;
-                    // tokio::task::spawn(async move { (self.handler)(ctx, ws, model, msg).await });
+        let failed = self
+            .read_while(async {
+                let mut failed = Vec::new();
+                while let Some(result) = tasks.join_next().await {
+                    failed.extend(result.err());
+                }
+                failed
+            })
+            .await;
+
+        for e in failed {
+            self.note_panic(e);
+        }
+    }
+}
+
+type Done = Shared<oneshot::Receiver<()>>;
+
+// Completion of the handler tasks that later ones have to wait for, see `DispatchMode`
+#[derive(Default)]
+struct Ordering {
+    channels: HashMap<Snowflake, Done>, // last task per channel
+    guilds: HashMap<Snowflake, GuildTasks>,
+}
+
+#[derive(Default)]
+struct GuildTasks {
+    last: Option<Done>,  // last guild-wide task
+    channels: Vec<Done>, // channel tasks started after it
+}
+
+impl Ordering {
+    // The channel and guild an event belongs to, if any. Guild and channel objects carry their
+    // own id in `id`
+    fn keys(msg: &DiscordMessage) -> (Option<Snowflake>, Option<Snowflake>) {
+        let id = |field: &str| msg.data[field].as_str()?.parse().ok();
+
+        match msg.event.as_deref() {
+            Some("GUILD_CREATE" | "GUILD_UPDATE" | "GUILD_DELETE") => (None, id("id")),
+            Some(
+                "CHANNEL_CREATE" | "CHANNEL_UPDATE" | "CHANNEL_DELETE" | "THREAD_CREATE" | "THREAD_UPDATE"
+                | "THREAD_DELETE",
+            ) => (id("id"), id("guild_id")),
+            _ => (id("channel_id"), id("guild_id")),
+        }
+    }
+
+    // Returns what a task for `channel`/`guild` waits for, `done` is when it finishes itself
+    fn enqueue(&mut self, channel: Option<Snowflake>, guild: Option<Snowflake>, done: Done) -> Vec<Done> {
+        self.prune();
+        let mut previous = Vec::new();
+
+        if let Some(guild) = guild {
+            let tasks = self.guilds.entry(guild).or_default();
+            previous.extend(tasks.last.clone());
+
+            match channel {
+                Some(_) => tasks.channels.push(done.clone()),
+                None => {
+                    // Guild-wide events wait for everything before them in the guild
+                    previous.append(&mut tasks.channels);
+                    tasks.last = Some(done.clone());
                 }
             }
+        }
 
-            dispatch_queue.clear();
-            tokio::time::sleep(Duration::from_millis(2)).await;
+        if let Some(channel) = channel {
+            previous.extend(self.channels.insert(channel, done));
         }
+
+        previous
+    }
+
+    fn prune(&mut self) {
+        let running = |done: &Done| done.clone().now_or_never().is_none();
+
+        self.channels.retain(|_, done| running(done));
+        self.guilds.retain(|_, tasks| {
+            tasks.channels.retain(running);
+            tasks.last = tasks.last.take().filter(running);
+            tasks.last.is_some() || !tasks.channels.is_empty()
+        });
+    }
+}
+
+async fn finish<O: HandlerResult>(handler: impl Future<Output = O>, catch_panics: bool) -> Result<()> {
+    if !catch_panics {
+        return handler.await.into_result();
+    }
+
+    match AssertUnwindSafe(handler).catch_unwind().await {
+        Ok(output) => output.into_result(),
+        Err(panic) => Err(Error::HandlerPanicked(panic_message(panic.as_ref())).into()),
     }
 }
 
@@ -195,22 +1049,296 @@ where
 impl<F, Model> Client<F, Model>
 where
     F: Future + Send + 'static,
-    F::Output: Send + 'static, Model: Send {
-    pub async fn resume<'a>(&mut self) -> Result<()> {
-        let mut ws = self.ws.lock().await;
-
-        {
+    F::Output: HandlerResult + Send + 'static, Model: Send {
+    /// Resumes the current session on a new connection, or identifies from scratch if there is none
+    pub async fn resume(&mut self) -> Result<()> {
+        let (info, token) = {
             let ctx = self.ctx.lock().await;
+            (ctx.resume_info.clone(), ctx.auth.clone())
+        };
+        let mut ws = self.ws.lock().await;
 
-            let seq = ws.sequence;
-            *ws = Websocket::new_with(
-                &(ctx.resume_info.as_ref().unwrap().url),
-                seq.unwrap(),
-            ).await?;
+        match (info, ws.sequence) {
+            (Some(info), Some(seq)) => {
+                let token = token.ok_or(Error::NoTokenGiven)?;
+                *ws = Websocket::new_with(&info.url, seq).await?;
+                ws.resume(&token, &info).await
+            }
+            _ => {
+                log::debug!("No session to resume, identifying instead");
+                *ws = Websocket::new().await?;
+                drop(ws);
+                self.identify().await
+            }
         }
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        // Nothing more is coming from the old connection
+        self.reader = None;
+        self.dispatch_lifecycle(Lifecycle::Disconnected).await;
+
+        let policy = self.policy.clone();
+        let shutdown = self.shutdown.clone();
+        let retry = policy.retry(async |attempt| {
+            self.dispatch_lifecycle(Lifecycle::Reconnecting { attempt }).await;
+            self.resume().await
+        });
 
-        ws.resume(self.ctx.clone()).await?;
+        // Shutting down shouldn't have to wait out the backoff, `run` stops once this returns
+        tokio::select! {
+            result = retry => result?,
+            _ = shutdown.wait() => {
+                log::debug!("Shut down while reconnecting");
+                return Ok(());
+            }
+        }
 
+        self.reader = Some(self.ws.lock().await.reader());
         Ok(())
     }
+
+    // The connection is still fine but the session is gone, so forget it and start over
+    async fn identify_again(&mut self) -> Result<()> {
+        let (token, presence) = {
+            let mut ctx = self.ctx.lock().await;
+            ctx.resume_info = None;
+            (ctx.auth.clone().ok_or(Error::NoTokenGiven)?, ctx.presence.clone())
+        };
+
+        if let Some(store) = &self.store {
+            store.clear()?;
+        }
+
+        // Discord wants a random 1-5s wait before identifying again
+        tokio::time::sleep(Duration::from_millis(fastrand::u64(1000..=5000))).await;
+
+        let mut ws = self.ws.lock().await;
+        ws.sequence = None;
+        ws.identify(&token, presence.as_ref()).await
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(s), _) => s.to_string(),
+        (_, Some(s)) => s.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const GUILD: u64 = 1;
+
+    fn task() -> (oneshot::Sender<()>, Done) {
+        let (done, finished) = oneshot::channel();
+        (done, finished.shared())
+    }
+
+    fn waits_for(previous: &[Done], tasks: &[&Done]) -> bool {
+        previous.len() == tasks.len() && tasks.iter().all(|t| previous.iter().any(|p| p.ptr_eq(t)))
+    }
+
+    fn dispatch(event: &str, data: Value) -> DiscordMessage {
+        DiscordMessage { op: 0, data, seq: Some(1), event: Some(event.to_string()) }
+    }
+
+    fn message(id: u64, channel: u64, content: &str) -> DiscordMessage {
+        dispatch("MESSAGE_CREATE", json!({
+            "id": id.to_string(), "channel_id": channel.to_string(), "content": content,
+            "author": { "id": "5", "username": "user", "global_name": null, "avatar": null },
+            "timestamp": "2024-05-01T12:34:56.789000+00:00", "edited_timestamp": null,
+            "tts": false, "mention_everyone": false, "mentions": [], "mention_roles": [],
+            "attachments": [], "embeds": [], "pinned": false, "type": 0,
+        }))
+    }
+
+    fn reaction(message: u64) -> DiscordMessage {
+        dispatch("MESSAGE_REACTION_ADD", json!({
+            "user_id": "5", "channel_id": "10", "message_id": message.to_string(),
+            "emoji": { "id": null, "name": "👍" },
+        }))
+    }
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn wait_for_listens_before_being_awaited() {
+        let listeners = Listeners::default();
+        let wait = listeners.wait_for(|m: &MessageCreate| m.content == "yes", WAIT);
+
+        listeners.feed(&reaction(1));
+        listeners.feed(&message(1, 10, "no"));
+        listeners.feed(&message(2, 10, "yes"));
+
+        assert_eq!(wait.await.unwrap().id, 2.into());
+    }
+
+    #[tokio::test]
+    async fn guild_settings_updates_reach_listeners() {
+        let listeners = Listeners::default();
+        let wait = listeners.wait_for(|s: &UserGuildSettings| s.muted, WAIT);
+
+        listeners.feed(&dispatch("USER_GUILD_SETTINGS_UPDATE", json!({
+            "guild_id": GUILD.to_string(), "muted": true, "mute_config": null, "message_notifications": 1,
+            "suppress_everyone": false, "suppress_roles": false, "mobile_push": true,
+            "channel_overrides": [], "version": 2,
+        })));
+
+        assert_eq!(wait.await.unwrap().guild_id, Some(GUILD.into()));
+    }
+
+    #[tokio::test]
+    async fn wait_for_times_out() {
+        let listeners = Listeners::default();
+        let wait = listeners.wait_for(|m: &MessageCreate| m.content == "yes", WAIT);
+        listeners.feed(&message(1, 10, "no"));
+
+        let err = wait.await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn collect_messages_stops_at_max() {
+        let listeners = Listeners::default();
+        let collect = listeners.collect_messages(10.into(), |m| m.content != "skip", 2, Duration::from_secs(10));
+
+        for (id, channel, content) in [(1, 10, "a"), (2, 11, "other channel"), (3, 10, "skip"), (4, 10, "b"), (5, 10, "c")] {
+            listeners.feed(&message(id, channel, content));
+        }
+
+        let ids: Vec<_> = collect.await.iter().map(|m| m.id).collect();
+        assert_eq!(ids, [1.into(), 4.into()]);
+    }
+
+    #[tokio::test]
+    async fn collectors_stop_when_the_duration_is_up() {
+        let listeners = Listeners::default();
+        let messages = listeners.collect_messages(10.into(), |_| true, 5, WAIT);
+        let reactions = listeners.collect_reactions(1.into(), WAIT);
+
+        listeners.feed(&message(1, 10, "a"));
+        listeners.feed(&reaction(1));
+        listeners.feed(&reaction(2));
+        listeners.feed(&reaction(1));
+
+        assert_eq!(messages.await.len(), 1);
+        assert_eq!(reactions.await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn ctx_collectors_dont_wait_for_the_lock() {
+        let ctx = Ctx::new(Context::default());
+        let guard = ctx.lock().await;
+
+        let wait = ctx.wait_for(|m: &MessageCreate| m.content == "yes", WAIT);
+        guard.listeners.feed(&message(1, 10, "yes"));
+        drop(guard);
+
+        assert!(wait.await.is_ok());
+    }
+
+    #[test]
+    fn channel_events_wait_for_guild_events() {
+        let mut ordering = Ordering::default();
+        let (_role_update, role_update_done) = task();
+        let (_message, message_done) = task();
+
+        assert!(ordering.enqueue(None, Some(GUILD.into()), role_update_done.clone()).is_empty());
+        let previous = ordering.enqueue(Some(10.into()), Some(GUILD.into()), message_done);
+        assert!(waits_for(&previous, &[&role_update_done]));
+    }
+
+    #[test]
+    fn guild_events_wait_for_channel_events() {
+        let mut ordering = Ordering::default();
+        let (_first, first_done) = task();
+        let (_second, second_done) = task();
+        let (_update, update_done) = task();
+        let (_after, after_done) = task();
+
+        ordering.enqueue(Some(10.into()), Some(GUILD.into()), first_done.clone());
+        ordering.enqueue(Some(11.into()), Some(GUILD.into()), second_done.clone());
+        let previous = ordering.enqueue(None, Some(GUILD.into()), update_done.clone());
+        assert!(waits_for(&previous, &[&first_done, &second_done]));
+
+        // The guild event already waits for the channel's earlier message
+        let previous = ordering.enqueue(Some(10.into()), Some(GUILD.into()), after_done);
+        assert!(waits_for(&previous, &[&update_done, &first_done]));
+    }
+
+    #[test]
+    fn guild_and_channel_objects_are_keyed_by_id() {
+        let guild_update = dispatch("GUILD_UPDATE", json!({ "id": GUILD.to_string(), "name": "renamed" }));
+        assert_eq!(Ordering::keys(&guild_update), (None, Some(GUILD.into())));
+
+        let channel_update = dispatch("CHANNEL_UPDATE", json!({ "id": "10", "guild_id": GUILD.to_string() }));
+        assert_eq!(Ordering::keys(&channel_update), (Some(10.into()), Some(GUILD.into())));
+
+        let dm_delete = dispatch("CHANNEL_DELETE", json!({ "id": "30" }));
+        assert_eq!(Ordering::keys(&dm_delete), (Some(30.into()), None));
+
+        // A message's own id isn't a channel
+        assert_eq!(Ordering::keys(&message(1, 10, "hi")), (Some(10.into()), None));
+    }
+
+    // What `dispatch` does for each handler in `DispatchMode::Concurrent`
+    fn spawn_ordered(
+        ordering: &mut Ordering,
+        tasks: &mut JoinSet<()>,
+        msg: &DiscordMessage,
+        delay: Duration,
+        ran: &Arc<std::sync::Mutex<Vec<String>>>,
+    ) {
+        let (channel, guild) = Ordering::keys(msg);
+        let (done, finished) = oneshot::channel();
+        let previous = ordering.enqueue(channel, guild, finished.shared());
+
+        let event = msg.event.clone().unwrap();
+        let ran = ran.clone();
+        tasks.spawn(async move {
+            join_all(previous).await;
+            tokio::time::sleep(delay).await;
+            ran.lock().unwrap().push(event);
+            drop(done);
+        });
+    }
+
+    #[tokio::test]
+    async fn guild_update_runs_before_later_messages() {
+        let mut ordering = Ordering::default();
+        let mut tasks = JoinSet::new();
+        let ran = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let mut message = message(1, 10, "hi");
+        message.data["guild_id"] = json!(GUILD.to_string());
+        let guild_update = dispatch("GUILD_UPDATE", json!({ "id": GUILD.to_string(), "name": "renamed" }));
+
+        // The slower guild update still finishes first
+        spawn_ordered(&mut ordering, &mut tasks, &guild_update, WAIT, &ran);
+        spawn_ordered(&mut ordering, &mut tasks, &message, Duration::ZERO, &ran);
+        while tasks.join_next().await.is_some() {}
+
+        assert_eq!(*ran.lock().unwrap(), ["GUILD_UPDATE", "MESSAGE_CREATE"]);
+    }
+
+    #[test]
+    fn unrelated_events_and_finished_tasks_dont_wait() {
+        let mut ordering = Ordering::default();
+        let (first, first_done) = task();
+        let (_other_guild, other_guild_done) = task();
+        let (_dm, dm_done) = task();
+        let (_next, next_done) = task();
+
+        ordering.enqueue(Some(10.into()), Some(GUILD.into()), first_done);
+        assert!(ordering.enqueue(Some(20.into()), Some(2.into()), other_guild_done).is_empty());
+        assert!(ordering.enqueue(Some(30.into()), None, dm_done).is_empty());
+
+        drop(first);
+        assert!(ordering.enqueue(None, Some(GUILD.into()), next_done).is_empty());
+    }
 }
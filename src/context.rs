@@ -1,26 +1,282 @@
+use std::{collections::{HashMap, VecDeque}, io::ErrorKind, ops::Deref, path::PathBuf, sync::Arc, time::Duration};
+
 use anyhow::Result;
+use chrono::Utc;
 use http::{HeaderMap, HeaderValue, Method, StatusCode};
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JSON;
+use tokio::sync::Mutex;
 
+use crate::client::{Listeners, ShutdownHandle};
 use crate::error::Error;
 use crate::types::{
-    guild::CachedGuild,
-    user::{BotUser, User},
+    channel::Channel,
+    gateway::ReadySupplemental,
+    guild::{CachedGuild, GuildMember, PartialGuildMember},
+    member_list::{GuildMemberListUpdate, MemberLists},
+    message::{Message, PartialMessage},
+    notifications::UserGuildSettings,
+    permissions::{compute_permissions, Permissions},
+    presence::{GatewayPresence, Presence},
+    role::Role,
+    settings::UserSettingsState,
+    timestamp::Timestamp,
+    user::{BotUser, PartialUser, User},
+    voice::VoiceConnectionInfo,
+    Snowflake,
 };
 
 static BASE_URL: &str = "https://discord.com/";
 
-#[derive(Debug)]
+/// How many of the latest messages `Cache::messages` keeps per channel
+pub const CACHED_MESSAGES: usize = 50;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ResumeInfo {
     pub url: String,
     pub id: String,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Session {
+    pub resume_info: ResumeInfo,
+    pub sequence: u64,
+    pub saved_at: Timestamp,
+
+    // Resuming doesn't send READY again, so keep who we were logged in as
+    pub user: Option<BotUser>,
+}
+
+impl Session {
+    pub fn age(&self) -> Duration {
+        (Utc::now().fixed_offset() - self.saved_at.0)
+            .to_std()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Somewhere to keep a [`Session`] between restarts
+///
+/// `Client` saves into it as the session progresses and tries to resume from it on login,
+/// see `Client::session_store`.
+pub trait SessionStore: Send + Sync {
+    fn load(&self) -> Result<Option<Session>>;
+    fn save(&self, session: &Session) -> Result<()>;
+    fn clear(&self) -> Result<()>;
+}
+
+/// Stores the session as json in a single file
+pub struct FileSessionStore {
+    path: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self) -> Result<Option<Session>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, session: &Session) -> Result<()> {
+        Ok(std::fs::write(&self.path, serde_json::to_vec(session)?)?)
+    }
+
+    fn clear(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Cache {
     pub users: Vec<User>,
     pub guilds: Vec<CachedGuild>,
+    pub private_channels: Vec<Channel>, // DMs and group DMs
+    pub member_lists: MemberLists,
+    pub voice: HashMap<Snowflake, VoiceConnectionInfo>, // our own connection per guild
+    pub presences: HashMap<Snowflake, Presence>, // by user id, latest one wins
+    pub guild_settings: HashMap<Option<Snowflake>, UserGuildSettings>, // None = DMs
+    pub messages: HashMap<Snowflake, VecDeque<Message>>, // by channel id, oldest first
+}
+
+impl Cache {
+    pub fn guild_mut(&mut self, id: Snowflake) -> Option<&mut CachedGuild> {
+        self.guilds.iter_mut().find(|g| g.id == id)
+    }
+
+    pub fn add_user(&mut self, user: User) {
+        match self.users.iter_mut().find(|u| u.id == user.id) {
+            Some(u) => *u = user,
+            None => self.users.push(user),
+        }
+    }
+
+    pub fn add_private_channel(&mut self, channel: Channel) {
+        match self.private_channels.iter_mut().find(|c| c.id == channel.id) {
+            Some(c) => *c = channel,
+            None => self.private_channels.push(channel),
+        }
+    }
+
+    // Only the latest `CACHED_MESSAGES` per channel are kept
+    pub fn add_message(&mut self, message: Message) {
+        let messages = self.messages.entry(message.channel_id).or_default();
+        if messages.len() == CACHED_MESSAGES {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    pub fn message(&self, channel_id: Snowflake, id: Snowflake) -> Option<&Message> {
+        self.messages.get(&channel_id)?.iter().find(|m| m.id == id)
+    }
+
+    // Edits to messages that aren't cached (anymore) are dropped
+    pub fn update_message(&mut self, partial: &PartialMessage) {
+        let Some(messages) = self.messages.get_mut(&partial.channel_id) else { return };
+        if let Some(message) = messages.iter_mut().find(|m| m.id == partial.id) {
+            partial.apply_to(message);
+        }
+    }
+
+    pub fn remove_message(&mut self, channel_id: Snowflake, id: Snowflake) {
+        if let Some(messages) = self.messages.get_mut(&channel_id) {
+            messages.retain(|m| m.id != id);
+        }
+    }
+
+    // Guild channels and threads go to their guild, DMs to `private_channels`
+    pub fn add_channel(&mut self, channel: Channel) {
+        if channel.is_dm() {
+            return self.add_private_channel(channel);
+        }
+
+        let Some(guild) = channel.guild_id.and_then(|id| self.guild_mut(id)) else { return };
+        let channels = if channel.is_thread() { &mut guild.threads } else { &mut guild.channels };
+        match channels.iter_mut().find(|c| c.id == channel.id) {
+            Some(c) => *c = channel,
+            None => channels.push(channel),
+        }
+    }
+
+    pub fn remove_channel(&mut self, guild_id: Option<Snowflake>, id: Snowflake) {
+        match guild_id.and_then(|id| self.guild_mut(id)) {
+            Some(guild) => {
+                guild.channels.retain(|c| c.id != id);
+                guild.threads.retain(|c| c.id != id);
+            }
+            None => self.private_channels.retain(|c| c.id != id),
+        }
+        self.messages.remove(&id);
+    }
+
+    pub fn add_role(&mut self, guild_id: Snowflake, role: Role) {
+        let Some(guild) = self.guild_mut(guild_id) else { return };
+        match guild.roles.iter_mut().find(|r| r.id == role.id) {
+            Some(r) => *r = role,
+            None => guild.roles.push(role),
+        }
+    }
+
+    // Members lose the role too, discord doesn't send member updates for it
+    pub fn remove_role(&mut self, guild_id: Snowflake, id: Snowflake) {
+        let Some(guild) = self.guild_mut(guild_id) else { return };
+        guild.roles.retain(|r| r.id != id);
+        for member in guild.members.iter_mut() {
+            member.roles.retain(|r| *r != id);
+        }
+    }
+
+    pub fn add_guild_settings(&mut self, settings: UserGuildSettings) {
+        self.guild_settings.insert(settings.guild_id, settings);
+    }
+
+    // A partial user can't be cached on its own, so only known users are updated
+    pub fn update_user(&mut self, partial: &PartialUser) {
+        if let Some(user) = self.users.iter_mut().find(|u| u.id == partial.id) {
+            partial.apply_to(user);
+        }
+    }
+
+    pub fn add_presence(&mut self, presence: Presence) {
+        self.presences.insert(presence.user.id, presence);
+    }
+
+    // `me` is the logged in user, whose member isn't matched through READY's users
+    pub fn apply_ready_supplemental(&mut self, supplemental: ReadySupplemental, me: Option<&BotUser>) {
+        let guild_ids: Vec<Snowflake> = supplemental.guilds.iter().map(|g| g.id).collect();
+        let me = me.map(User::from);
+
+        for (&guild_id, members) in guild_ids.iter().zip(supplemental.merged_members) {
+            let members: Vec<GuildMember> = members
+                .into_iter()
+                .map(|m| m.into_member(&self.users, me.as_ref()))
+                .filter(|m| m.user.is_some())
+                .collect();
+
+            self.add_members(guild_id, &members);
+        }
+
+        for (&guild_id, presences) in guild_ids.iter().zip(supplemental.merged_presences.guilds) {
+            for presence in presences {
+                self.add_presence(presence.into_presence(Some(guild_id)));
+            }
+        }
+
+        for presence in supplemental.merged_presences.friends {
+            self.add_presence(presence.into_presence(None));
+        }
+
+        for channel in supplemental.lazy_private_channels {
+            self.add_private_channel(channel);
+        }
+    }
+
+    pub fn apply_member_list_update(&mut self, update: GuildMemberListUpdate) {
+        let guild_id = update.guild_id;
+        let members: Vec<GuildMember> = update.members().map(|m| m.member.clone()).collect();
+
+        self.member_lists.apply(update);
+        self.add_members(guild_id, &members);
+    }
+
+    pub fn update_member(&mut self, guild_id: Snowflake, partial: &PartialGuildMember) {
+        let Some(user) = partial.user.clone() else { return };
+        let id = user.id;
+        self.add_user(user);
+
+        let Some(guild) = self.guild_mut(guild_id) else { return };
+        if let Some(member) = guild.members.iter_mut().find(|m| m.user.as_ref().is_some_and(|u| u.id == id)) {
+            partial.apply_to(member);
+        }
+    }
+
+    // Members replace any cached member with the same user
+    pub fn add_members(&mut self, guild_id: Snowflake, members: &[GuildMember]) {
+        for user in members.iter().filter_map(|m| m.user.clone()) {
+            self.add_user(user);
+        }
+
+        let Some(guild) = self.guild_mut(guild_id) else { return };
+        for member in members {
+            let id = member.user.as_ref().map(|u| u.id);
+            match guild.members.iter_mut().find(|m| id.is_some() && m.user.as_ref().map(|u| u.id) == id) {
+                Some(m) => *m = member.clone(),
+                None => guild.members.push(member.clone()),
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -29,6 +285,11 @@ pub struct Context {
     pub resume_info: Option<ResumeInfo>,
     pub cache: Cache,
     pub auth: Option<String>,
+    pub presence: Option<GatewayPresence>, // sent along with identify
+    pub settings: UserSettingsState,
+    pub preflight: bool, // check permissions against the cache before REST calls
+    pub shutdown: ShutdownHandle,
+    pub listeners: Listeners,
     client: reqwest::Client,
 }
 
@@ -52,10 +313,46 @@ impl Default for Context {
                 .user_agent("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/122.0.0.0 Safari/537.36")
                 .build().expect("Couldn't build client"),
             auth: None,
+            presence: None,
+            settings: UserSettingsState::default(),
+            preflight: false,
+            shutdown: ShutdownHandle::default(),
+            listeners: Listeners::default(),
         }
     }
 }
 
+/// The shared context handlers get, used like the `Arc<Mutex<Context>>` it derefs to
+///
+/// The listeners are kept next to the lock, so `Collectors` can start listening without
+/// waiting for whoever holds the context.
+#[derive(Clone, Debug)]
+pub struct Ctx {
+    context: Arc<Mutex<Context>>,
+    listeners: Listeners,
+}
+
+impl Ctx {
+    pub fn new(context: Context) -> Self {
+        Self {
+            listeners: context.listeners.clone(),
+            context: Arc::new(Mutex::new(context)),
+        }
+    }
+
+    pub fn listeners(&self) -> &Listeners {
+        &self.listeners
+    }
+}
+
+impl Deref for Ctx {
+    type Target = Arc<Mutex<Context>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.context
+    }
+}
+
 #[derive(Debug)]
 pub struct Response {
     pub status: StatusCode,
@@ -63,11 +360,114 @@ pub struct Response {
     pub body: JSON,
 }
 
+impl Response {
+    // For helpers that return parsed data instead of the response itself
+    pub fn error_for_status(self) -> Result<Self> {
+        if self.status.is_success() {
+            return Ok(self);
+        }
+
+        let message = self.body["message"].as_str().unwrap_or_default().to_string();
+        Err(Error::Http(self.status.as_u16(), message).into())
+    }
+}
+
 impl Context {
     pub fn set_auth(&mut self, token: String) {
         self.auth = Some(token);
     }
 
+    /// Fails with `Error::MissingPermissions` if the cache says we lack `required`
+    ///
+    /// Does nothing unless `preflight` is enabled. DMs have no permissions and are let through,
+    /// as is anything else the cache can't answer for (a guild or channel we don't know, our
+    /// member or its roles not being cached), so discord decides instead.
+    pub fn preflight(
+        &self,
+        guild_id: Option<Snowflake>,
+        channel_id: Option<Snowflake>,
+        required: Permissions,
+    ) -> Result<()> {
+        if !self.preflight {
+            return Ok(());
+        }
+        let unavailable = |reason: String| {
+            log::debug!("Skipping permission check: {reason}");
+            Ok(())
+        };
+        let Some(user) = self.user.as_ref() else {
+            return unavailable("our user isn't known before READY".to_string());
+        };
+
+        if let (None, Some(id)) = (guild_id, channel_id) {
+            if self.cache.private_channels.iter().any(|c| c.id == id) {
+                return Ok(());
+            }
+        }
+
+        let in_guild = |g: &&CachedGuild| match (guild_id, channel_id) {
+            (Some(id), _) => g.id == id,
+            (None, Some(id)) => g.channels.iter().chain(g.threads.iter()).any(|c| c.id == id),
+            (None, None) => false,
+        };
+        let Some(guild) = self.cache.guilds.iter().find(in_guild) else {
+            return unavailable(match (guild_id, channel_id) {
+                (Some(id), _) => format!("guild {id} isn't cached"),
+                (None, Some(id)) => format!("channel {id} isn't cached"),
+                (None, None) => "no guild or channel to check in".to_string(),
+            });
+        };
+
+        let Some(member) = guild.members.iter().find(|m| m.user.as_ref().is_some_and(|u| u.id == user.id)) else {
+            return unavailable(format!("our member in guild {} isn't cached", guild.id));
+        };
+        if member.roles.iter().any(|id| !guild.roles.iter().any(|r| r.id == *id)) {
+            return unavailable(format!("some of our roles in guild {} aren't cached", guild.id));
+        }
+
+        let channel = match channel_id {
+            Some(id) => match guild.channels.iter().chain(guild.threads.iter()).find(|c| c.id == id) {
+                Some(c) => Some(c),
+                None => return unavailable(format!("channel {id} isn't cached in guild {}", guild.id)),
+            },
+            None => None,
+        };
+
+        // Threads have their own versions of these
+        let mut required = required;
+        if channel.is_some_and(|c| c.is_thread()) {
+            if required.contains(Permissions::SEND_MESSAGES) {
+                required.remove(Permissions::SEND_MESSAGES);
+                required.insert(Permissions::SEND_MESSAGES_IN_THREADS);
+            }
+            if required.contains(Permissions::MANAGE_CHANNELS) {
+                required.remove(Permissions::MANAGE_CHANNELS);
+                required.insert(Permissions::MANAGE_THREADS);
+            }
+        }
+
+        let Some(permissions) = compute_permissions(guild, member, channel) else {
+            return unavailable(format!("the thread's parent channel isn't cached in guild {}", guild.id));
+        };
+        let missing = required - permissions;
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::MissingPermissions { required: missing, channel: channel_id }.into())
+        }
+    }
+
+    /// `preflight` for acting on a message, which also needs MANAGE_MESSAGES unless we sent it
+    ///
+    /// The author is looked up in the message cache, if the message isn't cached only
+    /// `required` is checked.
+    pub fn preflight_message(&self, channel_id: Snowflake, id: Snowflake, required: Permissions) -> Result<()> {
+        let author = self.cache.message(channel_id, id).and_then(|m| m.author.as_ref()).map(|a| a.id);
+        let theirs = author.is_some_and(|id| self.user.as_ref().is_some_and(|u| u.id != id));
+        let required = if theirs { required | Permissions::MANAGE_MESSAGES } else { required };
+        self.preflight(None, Some(channel_id), required)
+    }
+
     pub async fn request(
         &mut self,
         method: Method,
@@ -104,9 +504,14 @@ impl Context {
 
         match status.as_u16() {
             429 => {
-                Err(anyhow::anyhow!(Error::Ratelimit(
-                    std::time::Duration::from_secs(headers.get("Retry-After").unwrap().to_str().unwrap().parse::<u64>().unwrap() + 1)
-                )))
+                // The header is whole seconds, the body has the exact (fractional) value
+                let retry_after = headers
+                    .get("Retry-After")
+                    .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+                    .or_else(|| Some(serde_json::from_str::<JSON>(&text).ok()?["retry_after"].as_f64()?.ceil() as u64))
+                    .unwrap_or(1);
+
+                Err(anyhow::anyhow!(Error::Ratelimit(Duration::from_secs(retry_after + 1))))
             },
             _ => Ok(Response {
                 status,
@@ -116,3 +521,230 @@ impl Context {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn guild(id: u64) -> CachedGuild {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "joined_at": "2024-01-01T00:00:00+00:00",
+            "properties": {
+                "id": id.to_string(), "name": "guild", "owner_id": "1",
+                "preferred_locale": "en-US", "mfa_level": 0, "nsfw_level": 0, "verification_level": 0,
+                "default_message_notifications": 0, "explicit_content_filter": 0, "features": [],
+                "system_channel_flags": 0, "afk_timeout": 300, "premium_tier": 0,
+                "premium_progress_bar_enabled": false, "max_video_channel_users": 25,
+                "max_stage_video_channel_users": 50,
+            },
+            "large": false, "lazy": true, "member_count": 2, "premium_subscription_count": 0,
+            "channels": [], "threads": [], "stickers": [], "emojis": [], "roles": [],
+            "data_mode": "full", "version": 0,
+        }))
+        .unwrap()
+    }
+
+    const GUILD: u64 = 1;
+    const CHANNEL: u64 = 10;
+    const ME: u64 = 100;
+    const MODS: u64 = 50;
+
+    fn role(id: u64, permissions: Permissions) -> Role {
+        serde_json::from_value(json!({
+            "id": id.to_string(), "name": "role", "color": 0, "hoist": false, "position": 0,
+            "permissions": permissions.bits().to_string(), "managed": false, "mentionable": false, "flags": 0,
+        }))
+        .unwrap()
+    }
+
+    // `kind` 0 is a text channel and 11 a thread, `deny` is denied to @everyone
+    fn channel(id: u64, kind: u8, deny: Permissions) -> Channel {
+        serde_json::from_value(json!({
+            "id": id.to_string(), "type": kind, "flags": 0, "guild_id": GUILD.to_string(),
+            "parent_id": (kind == 11).then(|| CHANNEL.to_string()),
+            "permission_overwrites": [
+                { "id": GUILD.to_string(), "type": 0, "allow": "0", "deny": deny.bits().to_string() },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn message(id: u64, author: u64) -> Message {
+        serde_json::from_value(json!({
+            "id": id.to_string(), "channel_id": CHANNEL.to_string(), "content": "hi",
+            "author": { "id": author.to_string(), "username": "user" },
+            "timestamp": "2024-05-01T12:34:56.789000+00:00", "edited_timestamp": null,
+            "tts": false, "mention_everyone": false, "mentions": [], "mention_roles": [],
+            "attachments": [], "embeds": [], "pinned": false, "type": 0,
+        }))
+        .unwrap()
+    }
+
+    // Logged in as ME, with @everyone allowed to view and send in GUILD
+    fn preflight_ctx(roles: &[u64]) -> Context {
+        let mut ctx = Context { preflight: true, ..Default::default() };
+        ctx.user = Some(
+            serde_json::from_value(json!({
+                "id": ME.to_string(), "username": "me", "pronouns": "", "bio": "", "desktop": false,
+                "mobile": false, "verified": true, "premium": false, "mfa_enabled": false,
+                "nsfw_allowed": true, "premium_type": 0, "purchased_flags": 0, "flags": 0,
+            }))
+            .unwrap(),
+        );
+
+        let mut guild = guild(GUILD);
+        guild.roles.push(role(GUILD, Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES));
+        ctx.cache.guilds.push(guild);
+
+        let member: GuildMember = serde_json::from_value(json!({
+            "user": { "id": ME.to_string(), "username": "me" },
+            "roles": roles.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            "joined_at": "2024-01-01T00:00:00+00:00", "deaf": false, "mute": false, "flags": 0,
+        }))
+        .unwrap();
+        ctx.cache.add_members(GUILD.into(), &[member]);
+
+        ctx
+    }
+
+    fn missing(result: Result<()>) -> Option<Permissions> {
+        match result.err()?.downcast::<Error>() {
+            Ok(Error::MissingPermissions { required, .. }) => Some(required),
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[test]
+    fn preflight_follows_channel_updates() {
+        let mut ctx = preflight_ctx(&[]);
+        let send = |ctx: &Context| missing(ctx.preflight(None, Some(CHANNEL.into()), Permissions::SEND_MESSAGES));
+
+        // Unknown channels are left to discord
+        assert_eq!(send(&ctx), None);
+
+        ctx.cache.add_channel(channel(CHANNEL, 0, Permissions::SEND_MESSAGES));
+        assert_eq!(send(&ctx), Some(Permissions::SEND_MESSAGES));
+
+        ctx.cache.add_channel(channel(CHANNEL, 0, Permissions::empty()));
+        assert_eq!(ctx.cache.guilds[0].channels.len(), 1);
+        assert_eq!(send(&ctx), None);
+
+        ctx.cache.add_channel(channel(CHANNEL, 0, Permissions::SEND_MESSAGES));
+        ctx.cache.remove_channel(Some(GUILD.into()), CHANNEL.into());
+        assert!(ctx.cache.guilds[0].channels.is_empty());
+        assert_eq!(send(&ctx), None);
+    }
+
+    #[test]
+    fn threads_are_cached_with_their_guild() {
+        let mut ctx = preflight_ctx(&[]);
+        let thread = 11;
+        ctx.cache.add_channel(channel(thread, 11, Permissions::empty()));
+        assert_eq!(ctx.cache.guilds[0].threads.len(), 1);
+
+        // Sending in a thread needs its own permission, which @everyone doesn't have here
+        ctx.cache.add_channel(channel(CHANNEL, 0, Permissions::empty()));
+        let result = ctx.preflight(None, Some(thread.into()), Permissions::SEND_MESSAGES);
+        assert_eq!(missing(result), Some(Permissions::SEND_MESSAGES_IN_THREADS));
+    }
+
+    #[test]
+    fn preflight_follows_role_updates() {
+        let mut ctx = preflight_ctx(&[MODS]);
+        ctx.cache.add_channel(channel(CHANNEL, 0, Permissions::empty()));
+        ctx.cache.add_message(message(1, 200));
+        let delete = |ctx: &Context| missing(ctx.preflight_message(CHANNEL.into(), 1.into(), Permissions::VIEW_CHANNEL));
+
+        // Our role isn't known yet, so nothing is checked
+        assert_eq!(delete(&ctx), None);
+
+        ctx.cache.add_role(GUILD.into(), role(MODS, Permissions::empty()));
+        assert_eq!(delete(&ctx), Some(Permissions::MANAGE_MESSAGES));
+
+        ctx.cache.add_role(GUILD.into(), role(MODS, Permissions::MANAGE_MESSAGES));
+        assert_eq!(delete(&ctx), None);
+
+        ctx.cache.remove_role(GUILD.into(), MODS.into());
+        assert!(ctx.cache.guilds[0].members[0].roles.is_empty());
+        assert_eq!(delete(&ctx), Some(Permissions::MANAGE_MESSAGES));
+    }
+
+    #[test]
+    fn preflight_message_looks_up_author() {
+        let mut ctx = preflight_ctx(&[]);
+        ctx.cache.add_channel(channel(CHANNEL, 0, Permissions::empty()));
+        ctx.cache.add_message(message(1, ME));
+        ctx.cache.add_message(message(2, 200));
+        let delete = |id: u64| missing(ctx.preflight_message(CHANNEL.into(), id.into(), Permissions::VIEW_CHANNEL));
+
+        assert_eq!(delete(1), None);
+        assert_eq!(delete(2), Some(Permissions::MANAGE_MESSAGES));
+        // Not cached, so the author can't be checked
+        assert_eq!(delete(3), None);
+    }
+
+    fn user(id: u64) -> User {
+        serde_json::from_value(json!({ "id": id.to_string(), "username": "user" })).unwrap()
+    }
+
+    fn member_ids(cache: &Cache, guild_id: u64) -> Vec<Snowflake> {
+        let guild = cache.guilds.iter().find(|g| g.id == Snowflake::from(guild_id)).unwrap();
+        guild.members.iter().filter_map(|m| Some(m.user.as_ref()?.id)).collect()
+    }
+
+    #[test]
+    fn supplemental_is_matched_by_guild_id() {
+        let mut cache = Cache {
+            users: vec![user(100), user(200)],
+            guilds: vec![guild(1), guild(2)],
+            ..Default::default()
+        };
+
+        let merged_member = |user_id: u64| json!({
+            "user_id": user_id.to_string(), "roles": [], "joined_at": "2024-01-01T00:00:00+00:00",
+            "nick": null, "avatar": null, "premium_since": null, "deaf": false, "mute": false, "flags": 0,
+            "communication_disabled_until": null, "avatar_decoration_data": null,
+        });
+        let merged_presence = |user_id: u64| json!({ "user_id": user_id.to_string(), "status": "online" });
+
+        // Guilds come in a different order than the cache has them
+        let supplemental: ReadySupplemental = serde_json::from_value(json!({
+            "guilds": [{ "id": "2", "voice_states": [] }, { "id": "1", "voice_states": [] }],
+            "merged_members": [[merged_member(100)], [merged_member(200)]],
+            "merged_presences": {
+                "guilds": [[merged_presence(100)], [merged_presence(200)]],
+                "friends": [],
+            },
+            "lazy_private_channels": [],
+        }))
+        .unwrap();
+
+        cache.apply_ready_supplemental(supplemental, None);
+
+        assert_eq!(member_ids(&cache, 1), [Snowflake::from(200)]);
+        assert_eq!(member_ids(&cache, 2), [Snowflake::from(100)]);
+        assert_eq!(cache.presences[&Snowflake::from(100)].guild_id, Some(Snowflake::from(2)));
+        assert_eq!(cache.presences[&Snowflake::from(200)].guild_id, Some(Snowflake::from(1)));
+    }
+
+    #[test]
+    fn supplemental_for_unknown_guild_is_ignored() {
+        let mut cache = Cache { guilds: vec![guild(1)], users: vec![user(100)], ..Default::default() };
+
+        let supplemental: ReadySupplemental = serde_json::from_value(json!({
+            "guilds": [{ "id": "3" }],
+            "merged_members": [[{
+                "user_id": "100", "roles": [], "joined_at": "2024-01-01T00:00:00+00:00",
+                "nick": null, "avatar": null, "premium_since": null, "deaf": false, "mute": false,
+                "flags": 0, "communication_disabled_until": null, "avatar_decoration_data": null,
+            }]],
+            "merged_presences": {},
+        }))
+        .unwrap();
+
+        cache.apply_ready_supplemental(supplemental, None);
+        assert!(member_ids(&cache, 1).is_empty());
+    }
+}
@@ -2,6 +2,8 @@ use std::{fmt::Display, time::Duration};
 
 use thiserror::Error;
 
+use crate::types::{permissions::Permissions, Snowflake};
+
 #[derive(Error, Debug)]
 pub enum Error {
     NotLoggedIn,
@@ -9,6 +11,17 @@ pub enum Error {
     InvalidApiRequest(String),
     InvalidToken(String),
     Ratelimit(Duration),
+    NoSession,
+    ReconnectFailed(u32),
+    Timeout(Duration),
+    Http(u16, String),
+    SettingsOutOfDate(u32),
+    MalformedPayload(String),
+    HandlerPanicked(String),
+    MissingPermissions {
+        required: Permissions,
+        channel: Option<Snowflake>,
+    },
 }
 
 impl Display for Error {
@@ -19,6 +32,19 @@ impl Display for Error {
             Error::InvalidApiRequest(s) => f.write_fmt(format_args!("Invalid API request: {s}")),
             Error::InvalidToken(s) => f.write_fmt(format_args!("Token is invalid: {s}")),
             Error::Ratelimit(i) => f.write_fmt(format_args!("Rate limited until {i:?}")),
+            Error::NoSession => f.write_str("No gateway session to resume"),
+            Error::ReconnectFailed(n) => f.write_fmt(format_args!("Gave up reconnecting after {n} attempts")),
+            Error::Timeout(d) => f.write_fmt(format_args!("Timed out after {d:?}")),
+            Error::Http(s, m) => f.write_fmt(format_args!("Discord responded with {s}: {m}")),
+            Error::MissingPermissions { required, channel: Some(c) } => {
+                f.write_fmt(format_args!("Missing permissions {required} in channel {c}"))
+            }
+            Error::MissingPermissions { required, channel: None } => {
+                f.write_fmt(format_args!("Missing permissions {required}"))
+            }
+            Error::SettingsOutOfDate(n) => f.write_fmt(format_args!("Settings kept changing, gave up after {n} attempts")),
+            Error::MalformedPayload(s) => f.write_fmt(format_args!("Malformed payload: {s}")),
+            Error::HandlerPanicked(s) => f.write_fmt(format_args!("Handler panicked: {s}")),
         }
     }
 }
@@ -2,5 +2,6 @@ pub mod context;
 pub mod error;
 pub mod prelude;
 pub mod types;
+pub mod voice;
 pub mod websocket;
 pub mod client;
@@ -1,9 +1,10 @@
 pub use crate::{
-    context::Context,
+    context::{Context, Ctx, FileSessionStore, Session, SessionStore},
     error::Error,
     types::*,
-    websocket::{DiscordMessage, Websocket},
-    client::Client,
+    voice::VoiceConnection,
+    websocket::{DiscordMessage, Lifecycle, ReconnectPolicy, Websocket},
+    client::{Client, ClientBuilder, Collectors, DispatchMode, HandlerResult, Listeners, ShutdownHandle},
 };
 
 pub use anyhow::Result;
@@ -13,6 +14,5 @@ pub use serde_json::{self, json, Value};
 pub use tokio::sync::{Mutex, MutexGuard};
 pub use prost::Message as ProstMessage;
 
-pub type Ctx = Arc<Mutex<Context>>;
 pub type Ws = Arc<Mutex<Websocket>>;
 pub type Model<M> = Arc<Mutex<M>>;
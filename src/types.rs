@@ -1,16 +1,26 @@
 pub mod activity;
 pub mod channel;
 pub mod common;
+pub mod events;
+pub mod folders;
 pub mod gateway;
 pub mod guild;
+pub mod member_list;
 pub mod message;
+pub mod notifications;
+pub mod permissions;
 pub mod poll;
 pub mod presence;
 pub mod role;
+pub mod settings;
+pub mod status;
 pub mod sticker;
 pub mod timestamp;
 pub mod user;
+pub mod voice;
 
+mod enums;
+mod flags;
 mod vendor;
 mod snowflake;
 
@@ -1,19 +1,23 @@
+use anyhow::Result;
+use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use serde_repr::{Deserialize_repr, Serialize_repr};
-
-use super::common::Emoji;
-
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum ActivityType {
-    Playing = 0,      // Playing {name}
-    Steaming = 1,  // Streaming {details}
-    Listening = 2, // Listening to {name}
-    Watching = 3,  // Watching {name}
-    Custom = 4,    // {emoji} {state}
-    Competing
-    = 5, // Competing in {name}
+
+use super::{common::Emoji, Snowflake};
+use super::enums::wire_enum;
+use super::flags::wire_flags;
+
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum ActivityType {
+        Playing = 0,      // Playing {name}
+        Steaming = 1,  // Streaming {details}
+        Listening = 2, // Listening to {name}
+        Watching = 3,  // Watching {name}
+        Custom = 4,    // {emoji} {state}
+        Competing
+        = 5, // Competing in {name}
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -46,35 +50,56 @@ pub struct Secrets {
     pub match_key: Option<String>,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug)]
-#[repr(u16)]
-pub enum ActivityFlag {
-    Instance = 1 << 0,
-    Join = 1 << 1,
-    Spectate = 1 << 2,
-    JoinReq = 1 << 3,
-    Sync = 1 << 4,
-    Play = 1 << 5,
-    PrivacyFriends = 1 << 6,
-    PrivacyVC = 1 << 7,
-    Embedded = 1 << 8,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct ActivityFlag: u64 {
+        const INSTANCE = 1 << 0;
+        const JOIN = 1 << 1;
+        const SPECTATE = 1 << 2;
+        const JOIN_REQ = 1 << 3;
+        const SYNC = 1 << 4;
+        const PLAY = 1 << 5;
+        const PRIVACY_FRIENDS = 1 << 6;
+        const PRIVACY_VC = 1 << 7;
+        const EMBEDDED = 1 << 8;
+    }
 }
+wire_flags!(ActivityFlag);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(from = "ButtonRepr")]
 pub struct Button {
     pub label: String, // 1-32 limit
     pub url: String,   // 1-512 limit
 }
 
+// Presences we receive only carry the labels
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ButtonRepr {
+    Label(String),
+    Full { label: String, url: String },
+}
+
+impl From<ButtonRepr> for Button {
+    fn from(value: ButtonRepr) -> Self {
+        match value {
+            ButtonRepr::Label(label) => Self { label, url: String::new() },
+            ButtonRepr::Full { label, url } => Self { label, url },
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Activity {
     pub name: String,
     #[serde(rename = "type")]
     pub activity_type: ActivityType,
     pub url: Option<String>,
+    #[serde(default)]
     pub created_at: u64,
     pub timestamps: Option<Timestamps>,
-    pub application_id: u64, // Snowflake
+    pub application_id: Option<Snowflake>,
     pub details: Option<String>,
     pub state: Option<String>,
     pub emoji: Option<Emoji>,
@@ -83,7 +108,8 @@ pub struct Activity {
     pub secrets: Option<Secrets>,
     pub instance: Option<bool>,
     pub buttons: Option<Vec<Button>>,
-    pub flags: u16,
+    #[serde(default)]
+    pub flags: ActivityFlag,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -117,7 +143,7 @@ impl ActivityBuilder {
         self
     }
 
-    pub fn set_application_id(mut self, application_id: u64) -> Self {
+    pub fn set_application_id(mut self, application_id: Snowflake) -> Self {
         self.value["application_id"] = json!(application_id);
         self
     }
@@ -132,7 +158,7 @@ impl ActivityBuilder {
         self
     }
 
-    pub fn set_flags(mut self, flags: u16) -> Self {
+    pub fn set_flags(mut self, flags: ActivityFlag) -> Self {
         self.value["flags"] = json!(flags);
         self
     }
@@ -171,4 +197,9 @@ impl ActivityBuilder {
     pub fn build(self) -> Value {
         self.value
     }
+
+    // For places that want the typed activity, e.g. GatewayPresence
+    pub fn build_activity(self) -> Result<Activity> {
+        Ok(serde_json::from_value(self.value)?)
+    }
 }
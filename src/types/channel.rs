@@ -3,45 +3,58 @@ use crate::error::Error;
 use crate::types::timestamp::Timestamp;
 
 use anyhow::Result;
+use bitflags::bitflags;
 use http::Method;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 use tokio::sync::MutexGuard;
 
-use super::{user::User, Snowflake};
-
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum ChannelType {
-    Text = 0,
-    DM = 1,
-    Voice = 2,
-    Group = 3,
-    Category = 4,
-    Announcement = 5,
-    ThreadAnnouncement = 10,
-    ThreadPublic = 11,
-    ThreadPrivate = 12,
-    VoiceStage = 13,
-    Directory = 14,
-    Forum = 15,
-    Media = 16,
+use super::{permissions::Permissions, user::User, Snowflake};
+use super::enums::wire_enum;
+use super::flags::wire_flags;
+
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum ChannelType {
+        Text = 0,
+        DM = 1,
+        Voice = 2,
+        Group = 3,
+        Category = 4,
+        Announcement = 5,
+        ThreadAnnouncement = 10,
+        ThreadPublic = 11,
+        ThreadPrivate = 12,
+        VoiceStage = 13,
+        Directory = 14,
+        Forum = 15,
+        Media = 16,
+    }
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug)]
-#[repr(u16)]
-pub enum ChannelFlag {
-    Pinned = 1 << 1,
-    RequireTag = 1 << 4,
-    HideDownloadOptions = 1 << 15,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct ChannelFlag: u64 {
+        const GUILD_FEED_REMOVED = 1 << 0;
+        const PINNED = 1 << 1;
+        const ACTIVE_CHANNELS_REMOVED = 1 << 2;
+        const REQUIRE_TAG = 1 << 4;
+        const IS_SPAM = 1 << 5;
+        const IS_GUILD_RESOURCE_CHANNEL = 1 << 7;
+        const IS_SCHEDULED_FOR_DELETION = 1 << 9;
+        const SUMMARIES_DISABLED = 1 << 11;
+        const IS_BROADCASTING = 1 << 14;
+        const HIDE_MEDIA_DOWNLOAD_OPTIONS = 1 << 15;
+    }
 }
+wire_flags!(ChannelFlag);
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum VideoQualityMode {
-    Auto = 1, // "Not present"
-    Full = 2, // 720p
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum VideoQualityMode {
+        Auto = 1, // "Not present"
+        Full = 2, // 720p
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -52,15 +65,19 @@ pub struct ThreadMetadata {
     pub locked: bool,
 
     // Threads > 2022-01-09
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub create_timestamp: Option<Timestamp>,
 
     // Private threads
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub invitable: Option<bool>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ThreadMember {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user_id: Option<Snowflake>,
 
     pub join_timestamp: Timestamp,
@@ -68,20 +85,21 @@ pub struct ThreadMember {
                     // pub member: Option<GuildMember>,
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum OverwriteType {
-    Role = 0,
-    Member = 1,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum OverwriteType {
+        Role = 0,
+        Member = 1,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PermissionOverwrite {
     pub id: Snowflake,
-    pub allow: String, // TODO serialize into u128
-    pub deny: String,  // TODO serialize into u128
+    pub allow: Permissions,
+    pub deny: Permissions,
     #[serde(rename = "type")]
-    pub overwrite_type: u8,
+    pub overwrite_type: OverwriteType,
 }
 
 fn default_spam_value() -> bool {
@@ -91,59 +109,85 @@ fn default_spam_value() -> bool {
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Channel {
     pub id: Snowflake,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub topic: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub nsfw: Option<bool>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub position: Option<u64>,
 
     // VC
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub bitrate: Option<u64>, // in bits, obviously
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rate_limit_per_user: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rtc_region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub video_quality_mode: Option<VideoQualityMode>,
 
     // DM
-    #[serde(rename = "recipient_ids")]
+    #[serde(rename = "recipient_ids", skip_serializing_if = "Option::is_none")]
     pub users_ids: Option<Vec<Snowflake>>,
 
-    #[serde(rename = "recipients")]
+    #[serde(rename = "recipients", skip_serializing_if = "Option::is_none")]
     pub users: Option<Vec<User>>,
 
     // Group DM
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub application_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub managed: Option<bool>,
 
     // Group DM or thread
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub owner_id: Option<Snowflake>,
 
     // Thread
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub message_count: Option<u64>, // doesnt count first msg
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub member_count: Option<u64>,  // stops counting at 50
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub thread_metadata: Option<ThreadMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub member: Option<ThreadMember>, // only certain apis include this
 
     // Guild channels
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub guild_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub parent_id: Option<Snowflake>, // category id
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub last_message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub last_pin_timestamp: Option<Timestamp>,
 
-    #[serde(rename = "safety_warnings")]
+    #[serde(rename = "safety_warnings", skip_serializing_if = "Option::is_none")]
     pub warnings: Option<Vec<String>>,
 
-    #[serde(default = "default_spam_value")]
+    #[serde(default = "default_spam_value", skip_serializing_if = "std::ops::Not::not")]
     pub is_spam: bool,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub permission_overwrites: Option<Vec<PermissionOverwrite>>,
 
     #[serde(rename = "type")]
     pub channel_type: ChannelType,
-    pub flags: u64,
+    pub flags: ChannelFlag,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
 impl Channel {
@@ -173,7 +217,7 @@ impl Channel {
         self.channel_type == ChannelType::Category
     }
 
-    /**
+    /*
      * Channel
      */
 
@@ -195,11 +239,13 @@ impl Channel {
         ctx: &mut MutexGuard<'_, Context>,
         id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(None, Some(id), Permissions::MANAGE_CHANNELS)?;
+
         ctx.request(Method::DELETE, &format!("/v9/channels/{}", id), None)
             .await
     }
 
-    /**
+    /*
      * Message
      */
 
@@ -215,6 +261,8 @@ impl Channel {
         channel_id: Snowflake,
         msg: Value,
     ) -> Result<Response> {
+        ctx.preflight(None, Some(channel_id), Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES)?;
+
         ctx.request(
             Method::POST,
             &format!("/v9/channels/{}/messages", channel_id),
@@ -237,6 +285,8 @@ impl Channel {
         limit: u64,
         before: Option<Snowflake>,
     ) -> Result<Response> {
+        ctx.preflight(None, Some(channel_id), Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY)?;
+
         if limit > 100 {
             return Err(Error::InvalidApiRequest("limit must be less than 100".to_string()).into());
         }
@@ -262,6 +312,8 @@ impl Channel {
         channel_id: Snowflake,
         id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(None, Some(channel_id), Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY)?;
+
         ctx.request(
             Method::GET,
             &format!("/v9/channels/{}/messages/{}", channel_id, id),
@@ -282,6 +334,8 @@ impl Channel {
         channel_id: Snowflake,
         id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight_message(channel_id, id, Permissions::VIEW_CHANNEL)?;
+
         ctx.request(
             Method::DELETE,
             &format!("/v9/channels/{}/messages/{}", channel_id, id),
@@ -290,6 +344,7 @@ impl Channel {
         .await
     }
 
+    /// Others' messages can only have their flags edited, which needs MANAGE_MESSAGES
     pub async fn edit_message(
         self,
         ctx: &mut MutexGuard<'_, Context>,
@@ -304,6 +359,8 @@ impl Channel {
         id: Snowflake,
         msg: Value
     ) -> Result<Response> {
+        ctx.preflight_message(channel_id, id, Permissions::VIEW_CHANNEL)?;
+
         ctx.request(
             Method::PATCH,
             &format!("/v9/channels/{}/messages/{}", channel_id, id),
@@ -326,6 +383,8 @@ impl Channel {
         id: Snowflake,
         emoji: &str
     ) -> Result<Response> {
+        ctx.preflight(None, Some(channel_id), Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY)?;
+
         ctx.request(
             Method::PUT,
             &format!("/v9/channels/{}/messages/{}/reactions/{}/@me", channel_id, id, emoji),
@@ -353,6 +412,7 @@ pub mod welcome_screen {
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct WelcomeScreen {
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub description: Option<String>,
         #[serde(rename = "welcome_channels")]
         pub channels: Vec<WelcomeChannel>,
@@ -363,22 +423,25 @@ pub mod welcome_screen {
         pub channel_id: Snowflake,
         pub description: String,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub emoji_id: Option<Snowflake>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub emoji_name: Option<String>,
     }
 }
 
 pub mod stage_instance {
     use serde::{Deserialize, Serialize};
-    use serde_repr::{Deserialize_repr, Serialize_repr};
 
     use crate::types::Snowflake;
+    use crate::types::enums::wire_enum;
 
-    #[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-    #[repr(u8)]
-    pub enum PrivacyLevel {
-        Public = 1,
-        GuildOnly = 2,
+    wire_enum! {
+        #[derive(Debug, Eq, PartialEq, Clone)]
+        pub enum PrivacyLevel {
+            Public = 1,
+            GuildOnly = 2,
+        }
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -388,6 +451,7 @@ pub mod stage_instance {
         pub channel_id: Snowflake,
         pub topic: String,
         pub privacy_level: PrivacyLevel,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub guild_scheduled_event_id: Option<Snowflake>,
     }
 }
@@ -453,3 +517,105 @@ impl ChannelBuilder {
         self.value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::types::common::assert_round_trips;
+
+    // A text channel from GUILD_CREATE, with the nulls discord sends for unset fields
+    fn text_channel() -> Value {
+        json!({
+            "id": "1097941239282155521",
+            "type": 0,
+            "guild_id": "1097941238770434088",
+            "name": "general",
+            "position": 0,
+            "parent_id": null,
+            "topic": null,
+            "nsfw": false,
+            "rate_limit_per_user": 0,
+            "last_message_id": "1235171046398578749",
+            "last_pin_timestamp": "2024-04-30T09:00:00+00:00",
+            "flags": 0,
+            "permission_overwrites": [
+                { "id": "1097941238770434088", "type": 0, "allow": "0", "deny": "2048" },
+                { "id": "1097940928617857095", "type": 1, "allow": "8192", "deny": "0" },
+            ],
+            "icon_emoji": { "id": null, "name": "👋" },
+            "theme_color": null,
+        })
+    }
+
+    // A voice channel has no topic, a thread no overwrites or name of its parent
+    fn voice_channel() -> Value {
+        json!({
+            "id": "1097941239282155522",
+            "type": 2,
+            "guild_id": "1097941238770434088",
+            "name": "Voice",
+            "position": 1,
+            "parent_id": "1097941239282155520",
+            "bitrate": 64000,
+            "user_limit": 0,
+            "rtc_region": null,
+            "flags": 0,
+            "permission_overwrites": [],
+        })
+    }
+
+    fn thread() -> Value {
+        json!({
+            "id": "1235171046398578800",
+            "type": 11,
+            "guild_id": "1097941238770434088",
+            "parent_id": "1097941239282155521",
+            "owner_id": "1097940928617857095",
+            "name": "a thread",
+            "last_message_id": "1235171046398578801",
+            "message_count": 3,
+            "member_count": 2,
+            "rate_limit_per_user": 0,
+            "flags": 0,
+            "thread_metadata": {
+                "archived": false,
+                "auto_archive_duration": 4320,
+                "archive_timestamp": "2024-05-01T12:34:56.789000+00:00",
+                "locked": false,
+                "create_timestamp": "2024-05-01T12:34:56.789000+00:00",
+            },
+        })
+    }
+
+    fn group_dm() -> Value {
+        json!({
+            "id": "1235171046398578900",
+            "type": 3,
+            "name": null,
+            "icon": null,
+            "owner_id": "1097940928617857095",
+            "last_message_id": null,
+            "recipient_ids": ["1097940928617857096", "1097940928617857097"],
+            "flags": 0,
+        })
+    }
+
+    #[test]
+    fn captured_channels_round_trip() {
+        for payload in [text_channel(), voice_channel(), thread(), group_dm()] {
+            assert_round_trips::<Channel>(payload);
+        }
+    }
+
+    #[test]
+    fn missing_fields_stay_missing() {
+        let channel: Channel = serde_json::from_value(voice_channel()).unwrap();
+        let output = serde_json::to_value(&channel).unwrap();
+
+        for key in ["topic", "icon", "last_message_id", "is_spam"] {
+            assert!(output.get(key).is_none(), "{key} got serialized");
+        }
+    }
+}
@@ -2,24 +2,35 @@ use crate::types::channel::Channel;
 use crate::types::guild::GuildMember;
 use crate::types::message::{Attachment, Message};
 use crate::types::role::Role;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 
 use super::{user::User, Snowflake};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Emoji {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>, // null in reaction emojis objects
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub roles: Option<Vec<Snowflake>>,
 
-    #[serde(rename = "user")]
+    #[serde(rename = "user", skip_serializing_if = "Option::is_none")]
     pub creator: Option<User>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub require_colons: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub managed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub animated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub available: Option<bool>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -31,3 +42,75 @@ pub struct Resolved {
     pub messages: Option<HashMap<Snowflake, Message>>,
     pub attachments: Option<HashMap<Snowflake, Attachment>>,
 }
+
+/// For fields of partial objects that can be null: missing is `None`, null is `Some(None)`
+///
+/// Use with `#[serde(default, deserialize_with = "nullable")]`.
+pub fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// Copies every field the partial object has (`Some`) over to the full one
+macro_rules! merge_fields {
+    ($partial:expr => $full:expr; $($field:ident),* $(,)?) => {
+        $(
+            if let Some(value) = &$partial.$field {
+                $full.$field = value.clone();
+            }
+        )*
+
+        #[cfg(feature = "extra-fields")]
+        $full.extra.extend($partial.extra.clone());
+    };
+}
+
+pub(crate) use merge_fields;
+
+/// Checks that `payload` comes back out of `T` as it went in
+///
+/// Without `extra-fields` only unmodeled fields may go missing. Missing and null both
+/// deserialize to `None`, so a null field may come back missing either way.
+#[cfg(test)]
+pub(crate) fn assert_round_trips<T: serde::de::DeserializeOwned + Serialize>(payload: serde_json::Value) {
+    use serde_json::Value;
+
+    fn assert_subset(output: &Value, input: &Value, path: &str) {
+        match (output, input) {
+            (Value::Object(output), Value::Object(input)) => {
+                for (key, value) in output {
+                    let Some(original) = input.get(key) else {
+                        panic!("{path}.{key} wasn't in the payload but got serialized as {value}");
+                    };
+                    assert_subset(value, original, &format!("{path}.{key}"));
+                }
+            }
+            (Value::Array(output), Value::Array(input)) => {
+                assert_eq!(output.len(), input.len(), "{path} changed length");
+                for (i, (value, original)) in output.iter().zip(input).enumerate() {
+                    assert_subset(value, original, &format!("{path}[{i}]"));
+                }
+            }
+            _ => assert_eq!(output, input, "{path} changed"),
+        }
+    }
+
+    #[cfg(feature = "extra-fields")]
+    fn without_nulls(value: Value) -> Value {
+        match value {
+            Value::Object(map) => map.into_iter().filter(|(_, v)| !v.is_null()).map(|(k, v)| (k, without_nulls(v))).collect(),
+            Value::Array(values) => values.into_iter().map(without_nulls).collect(),
+            value => value,
+        }
+    }
+
+    let parsed: T = serde_json::from_value(payload.clone()).unwrap();
+    let output = serde_json::to_value(&parsed).unwrap();
+
+    assert_subset(&output, &payload, "payload");
+    #[cfg(feature = "extra-fields")]
+    assert_eq!(without_nulls(output), without_nulls(payload));
+}
@@ -0,0 +1,188 @@
+/// Integer-backed wire enum with an `Unknown` fallback
+///
+/// Values discord adds later deserialize to `Unknown` and serialize back unchanged.
+macro_rules! wire_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $value:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($(#[$vmeta])* $variant,)*
+            Unknown(u64),
+        }
+
+        impl $name {
+            pub fn value(&self) -> u64 {
+                match self {
+                    $(Self::$variant => $value,)*
+                    Self::Unknown(value) => *value,
+                }
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(value: u64) -> Self {
+                match value {
+                    $($value => Self::$variant,)*
+                    value => Self::Unknown(value),
+                }
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(value: $name) -> Self {
+                value.value()
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_u64(self.value())
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                Ok(Self::from(u64::deserialize(deserializer)?))
+            }
+        }
+    };
+}
+
+pub(crate) use wire_enum;
+
+/// String-backed wire enum with an `Unknown` fallback, see `wire_enum`
+macro_rules! wire_str_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $value:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($(#[$vmeta])* $variant,)*
+            Unknown(String),
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $(Self::$variant => $value,)*
+                    Self::Unknown(value) => value,
+                }
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(value: &str) -> Self {
+                match value {
+                    $($value => Self::$variant,)*
+                    value => Self::Unknown(value.to_string()),
+                }
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                Ok(Self::from(String::deserialize(deserializer)?.as_str()))
+            }
+        }
+    };
+}
+
+pub(crate) use wire_str_enum;
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::types::{channel::ChannelType, presence::StatusType};
+
+    wire_enum! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Kind {
+            First = 1,
+            Second = 2,
+        }
+    }
+
+    wire_str_enum! {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        enum Name {
+            First = "first",
+            Second = "second",
+        }
+    }
+
+    #[test]
+    fn known_values_round_trip() {
+        let kind: Kind = serde_json::from_value(json!(2)).unwrap();
+        assert_eq!(kind, Kind::Second);
+        assert_eq!(serde_json::to_value(kind).unwrap(), json!(2));
+    }
+
+    #[test]
+    fn unknown_values_round_trip() {
+        let kind: Kind = serde_json::from_value(json!(46)).unwrap();
+        assert_eq!(kind, Kind::Unknown(46));
+        assert_eq!(kind.value(), 46);
+        assert_eq!(serde_json::to_value(kind).unwrap(), json!(46));
+
+        let channel: ChannelType = serde_json::from_value(json!(99)).unwrap();
+        assert_eq!(channel, ChannelType::Unknown(99));
+        assert_eq!(serde_json::to_value(channel).unwrap(), json!(99));
+    }
+
+    #[test]
+    fn non_integers_are_rejected() {
+        assert!(serde_json::from_value::<Kind>(json!("first")).is_err());
+        assert!(serde_json::from_value::<Kind>(json!(-1)).is_err());
+    }
+
+    #[test]
+    fn known_strings_round_trip() {
+        let name: Name = serde_json::from_value(json!("second")).unwrap();
+        assert_eq!(name, Name::Second);
+        assert_eq!(serde_json::to_value(name).unwrap(), json!("second"));
+    }
+
+    #[test]
+    fn unknown_strings_round_trip() {
+        let name: Name = serde_json::from_value(json!("third")).unwrap();
+        assert_eq!(name, Name::Unknown("third".to_string()));
+        assert_eq!(name.as_str(), "third");
+        assert_eq!(serde_json::to_value(name).unwrap(), json!("third"));
+
+        let status: StatusType = serde_json::from_value(json!("streaming")).unwrap();
+        assert_eq!(status, StatusType::Unknown("streaming".to_string()));
+        assert_eq!(status.to_string(), "streaming");
+        assert_eq!(serde_json::to_value(status).unwrap(), json!("streaming"));
+    }
+
+    #[test]
+    fn non_strings_are_rejected() {
+        assert!(serde_json::from_value::<Name>(json!(1)).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::ops::Deref;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    common::Emoji,
+    gateway::Ready,
+    guild::PartialGuildMember,
+    message::{Message, PartialMessage},
+    notifications::UserGuildSettings,
+    presence::Presence,
+    Snowflake,
+};
+
+/// A dispatch event with a typed payload, see `Listeners::wait_for`
+pub trait Event: DeserializeOwned + Send + 'static {
+    const NAME: &'static str;
+}
+
+macro_rules! event {
+    ($ty:ty, $name:literal) => {
+        impl Event for $ty {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MessageCreate {
+    #[serde(flatten)]
+    pub message: Message,
+    pub member: Option<PartialGuildMember>, // guild messages only, without `user`
+}
+
+impl Deref for MessageCreate {
+    type Target = Message;
+
+    fn deref(&self) -> &Self::Target {
+        &self.message
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MessageReactionAdd {
+    pub user_id: Snowflake,
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+    pub member: Option<PartialGuildMember>,
+    pub emoji: Emoji,
+    pub message_author_id: Option<Snowflake>,
+    #[serde(default)]
+    pub burst: bool, // super reaction
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MessageDelete {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+}
+
+event!(Ready, "READY");
+event!(MessageCreate, "MESSAGE_CREATE");
+event!(PartialMessage, "MESSAGE_UPDATE");
+event!(MessageDelete, "MESSAGE_DELETE");
+event!(MessageReactionAdd, "MESSAGE_REACTION_ADD");
+event!(PartialGuildMember, "GUILD_MEMBER_UPDATE");
+event!(Presence, "PRESENCE_UPDATE");
+event!(UserGuildSettings, "USER_GUILD_SETTINGS_UPDATE");
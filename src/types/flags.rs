@@ -0,0 +1,32 @@
+/// Integer (de)serialization and Display for a `bitflags!` type
+///
+/// Unknown bits are kept, so flags discord adds later survive a round trip.
+macro_rules! wire_flags {
+    ($name:ident) => {
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                bitflags::parser::to_writer(self, f)
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_u64(self.bits())
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                Ok(Self::from_bits_retain(u64::deserialize(deserializer)?))
+            }
+        }
+    };
+}
+
+pub(crate) use wire_flags;
@@ -0,0 +1,354 @@
+use anyhow::Result;
+use tokio::sync::MutexGuard;
+
+use crate::{context::Context, error::Error};
+
+use super::{
+    discord_proto::{
+        preloaded_user_settings::{GuildFolder, GuildFolders},
+        PreloadedUserSettings,
+    },
+    settings::UserSettings,
+    Snowflake,
+};
+
+#[derive(Debug, Clone)]
+pub struct FolderGuild {
+    pub id: Snowflake,
+    pub name: Option<String>, // None if the guild isn't cached
+}
+
+/// One entry of the guild sidebar
+///
+/// Guilds that aren't in a folder are entries with no `id` and a single guild.
+#[derive(Debug, Clone)]
+pub struct Folder {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+    pub color: Option<u64>,
+    pub guilds: Vec<FolderGuild>,
+}
+
+/// Guild sidebar layout, stored in the settings proto
+///
+/// Every change goes through `UserSettings::update`, positions are indexes into the sidebar
+/// (or into a folder) and are clamped to its length.
+pub struct Folders;
+
+impl Folders {
+    pub async fn list(ctx: &mut MutexGuard<'_, Context>) -> Result<Vec<Folder>> {
+        let settings = match ctx.settings.preloaded.clone() {
+            Some(settings) => settings,
+            None => UserSettings::fetch::<PreloadedUserSettings>(ctx).await?,
+        };
+
+        let folders = settings.guild_folders.map(|f| f.folders).unwrap_or_default();
+        Ok(folders
+            .into_iter()
+            .map(|f| Folder {
+                id: f.id,
+                name: f.name,
+                color: f.color,
+                guilds: f
+                    .guild_ids
+                    .into_iter()
+                    .map(|id| FolderGuild {
+                        id: id.into(),
+                        name: ctx
+                            .cache
+                            .guilds
+                            .iter()
+                            .find(|g| u64::from(g.id) == id)
+                            .map(|g| g.properties.name.clone()),
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Creates a folder holding `guilds` where the first of them used to be, returns its id
+    pub async fn create(
+        ctx: &mut MutexGuard<'_, Context>,
+        name: &str,
+        color: Option<u64>,
+        guilds: Vec<Snowflake>,
+    ) -> Result<i64> {
+        if guilds.is_empty() {
+            return Err(Error::InvalidApiRequest("a folder needs at least one guild".to_string()).into());
+        }
+        Self::validate_guilds(ctx, &guilds)?;
+
+        let mut id = 0;
+        Self::update(ctx, |folders| {
+            id = loop {
+                let id = fastrand::i64(1..1 << 32);
+                if !folders.folders.iter().any(|f| f.id == Some(id)) {
+                    break id;
+                }
+            };
+
+            Self::insert_folder(folders, GuildFolder {
+                guild_ids: guilds.iter().map(|g| u64::from(*g)).collect(),
+                id: Some(id),
+                name: Some(name.to_string()),
+                color,
+            });
+            Ok(())
+        })
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Moves a guild into `folder` at `position`, or out of any folder when `folder` is None
+    pub async fn move_guild(
+        ctx: &mut MutexGuard<'_, Context>,
+        guild: Snowflake,
+        folder: Option<i64>,
+        position: usize,
+    ) -> Result<()> {
+        Self::validate_guilds(ctx, &[guild])?;
+        Self::update(ctx, |folders| Self::place_guild(folders, guild, folder, position)).await
+    }
+
+    /// Moves a whole folder to `position` in the sidebar
+    pub async fn move_folder(ctx: &mut MutexGuard<'_, Context>, folder: i64, position: usize) -> Result<()> {
+        Self::update(ctx, |folders| {
+            let entry = folders.folders.remove(Self::folder_index(folders, folder)?);
+            folders.folders.insert(position.min(folders.folders.len()), entry);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes a folder, its guilds stay where it was
+    pub async fn remove(ctx: &mut MutexGuard<'_, Context>, folder: i64) -> Result<()> {
+        Self::update(ctx, |folders| Self::ungroup(folders, folder)).await
+    }
+
+    // Applies `f` to a copy so a failed validation leaves the settings untouched
+    async fn update(
+        ctx: &mut MutexGuard<'_, Context>,
+        mut f: impl FnMut(&mut GuildFolders) -> Result<()>,
+    ) -> Result<()> {
+        let mut result = Ok(());
+
+        UserSettings::update(ctx, |s: &mut PreloadedUserSettings| {
+            let mut folders = s.guild_folders.clone().unwrap_or_default();
+            result = f(&mut folders);
+
+            if result.is_ok() {
+                Self::sync_positions(&mut folders);
+                s.guild_folders = Some(folders);
+            }
+        })
+        .await?;
+
+        result
+    }
+
+    // Puts `folder` where the first of its guilds used to be, taking its guilds out of other entries
+    fn insert_folder(folders: &mut GuildFolders, folder: GuildFolder) {
+        let guilds = &folder.guild_ids;
+
+        // Entries before the first guild that disappear once the guilds are taken out
+        let position = Self::position_of(folders, guilds[0].into()).unwrap_or(0);
+        let emptied = folders.folders[..position]
+            .iter()
+            .filter(|f| f.guild_ids.iter().all(|id| guilds.contains(id)))
+            .count();
+        let position = position - emptied;
+
+        for guild in guilds.iter() {
+            Self::take_guild(folders, (*guild).into());
+        }
+
+        folders.folders.insert(position.min(folders.folders.len()), folder);
+    }
+
+    fn place_guild(folders: &mut GuildFolders, guild: Snowflake, folder: Option<i64>, position: usize) -> Result<()> {
+        if let Some(id) = folder {
+            // Taking out a folder's only guild would remove the folder itself
+            if folders.folders[Self::folder_index(folders, id)?].guild_ids == [u64::from(guild)] {
+                return Ok(());
+            }
+        }
+
+        Self::take_guild(folders, guild);
+
+        match folder {
+            Some(id) => {
+                let index = Self::folder_index(folders, id)?;
+                let target = &mut folders.folders[index];
+                target.guild_ids.insert(position.min(target.guild_ids.len()), guild.into());
+            }
+            None => {
+                let entry = GuildFolder { guild_ids: vec![guild.into()], ..Default::default() };
+                folders.folders.insert(position.min(folders.folders.len()), entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn ungroup(folders: &mut GuildFolders, folder: i64) -> Result<()> {
+        let index = Self::folder_index(folders, folder)?;
+        let removed = folders.folders.remove(index);
+
+        for (i, guild) in removed.guild_ids.into_iter().enumerate() {
+            let entry = GuildFolder { guild_ids: vec![guild], ..Default::default() };
+            folders.folders.insert(index + i, entry);
+        }
+        Ok(())
+    }
+
+    // `guild_positions` is the older flat sidebar order, clients still read it when it's set
+    fn sync_positions(folders: &mut GuildFolders) {
+        folders.guild_positions = folders.folders.iter().flat_map(|f| f.guild_ids.iter().copied()).collect();
+    }
+
+    fn validate_guilds(ctx: &MutexGuard<'_, Context>, guilds: &[Snowflake]) -> Result<()> {
+        match guilds.iter().find(|id| !ctx.cache.guilds.iter().any(|g| g.id == **id)) {
+            Some(id) => Err(Error::InvalidApiRequest(format!("not in guild {id}")).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn folder_index(folders: &GuildFolders, id: i64) -> Result<usize> {
+        folders
+            .folders
+            .iter()
+            .position(|f| f.id == Some(id))
+            .ok_or(Error::InvalidApiRequest(format!("no folder with id {id}")).into())
+    }
+
+    fn position_of(folders: &GuildFolders, guild: Snowflake) -> Option<usize> {
+        folders.folders.iter().position(|f| f.guild_ids.contains(&guild.into()))
+    }
+
+    // Removes the guild from wherever it is, along with any entry that ends up empty
+    fn take_guild(folders: &mut GuildFolders, guild: Snowflake) {
+        for folder in folders.folders.iter_mut() {
+            folder.guild_ids.retain(|id| *id != u64::from(guild));
+        }
+        folders.folders.retain(|f| !f.guild_ids.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOLDER: i64 = 10;
+
+    fn entry(id: Option<i64>, guilds: &[u64]) -> GuildFolder {
+        GuildFolder { guild_ids: guilds.to_vec(), id, ..Default::default() }
+    }
+
+    // 1, folder 10 with 2 and 3, 4, 5
+    fn sidebar() -> GuildFolders {
+        GuildFolders {
+            folders: vec![entry(None, &[1]), entry(Some(FOLDER), &[2, 3]), entry(None, &[4]), entry(None, &[5])],
+            guild_positions: vec![],
+        }
+    }
+
+    fn layout(folders: &GuildFolders) -> Vec<(Option<i64>, Vec<u64>)> {
+        folders.folders.iter().map(|f| (f.id, f.guild_ids.clone())).collect()
+    }
+
+    fn create(folders: &mut GuildFolders, guilds: &[u64]) {
+        Folders::insert_folder(folders, entry(Some(1), guilds));
+    }
+
+    #[test]
+    fn create_takes_place_of_first_guild() {
+        let mut folders = sidebar();
+        create(&mut folders, &[4, 5]);
+        assert_eq!(layout(&folders), [(None, vec![1]), (Some(FOLDER), vec![2, 3]), (Some(1), vec![4, 5])]);
+    }
+
+    #[test]
+    fn create_skips_entries_emptied_before_it() {
+        // 1 is taken out from before 5, so the folder lands one entry earlier
+        let mut folders = sidebar();
+        create(&mut folders, &[5, 1]);
+        assert_eq!(layout(&folders), [(Some(FOLDER), vec![2, 3]), (None, vec![4]), (Some(1), vec![5, 1])]);
+    }
+
+    #[test]
+    fn create_from_folder_guilds() {
+        let mut folders = sidebar();
+        create(&mut folders, &[2]);
+        assert_eq!(
+            layout(&folders),
+            [(None, vec![1]), (Some(1), vec![2]), (Some(FOLDER), vec![3]), (None, vec![4]), (None, vec![5])]
+        );
+
+        // Taking every guild out of a folder removes it
+        let mut folders = sidebar();
+        create(&mut folders, &[3, 2]);
+        assert_eq!(layout(&folders), [(None, vec![1]), (Some(1), vec![3, 2]), (None, vec![4]), (None, vec![5])]);
+    }
+
+    #[test]
+    fn move_within_same_folder() {
+        let mut folders = sidebar();
+        Folders::place_guild(&mut folders, 2.into(), Some(FOLDER), 1).unwrap();
+        assert_eq!(folders.folders[1].guild_ids, [3, 2]);
+
+        // Positions past the end are clamped
+        Folders::place_guild(&mut folders, 3.into(), Some(FOLDER), 5).unwrap();
+        assert_eq!(folders.folders[1].guild_ids, [2, 3]);
+    }
+
+    #[test]
+    fn move_into_and_out_of_folder() {
+        let mut folders = sidebar();
+        Folders::place_guild(&mut folders, 4.into(), Some(FOLDER), 0).unwrap();
+        assert_eq!(layout(&folders), [(None, vec![1]), (Some(FOLDER), vec![4, 2, 3]), (None, vec![5])]);
+
+        Folders::place_guild(&mut folders, 2.into(), None, 0).unwrap();
+        assert_eq!(layout(&folders), [(None, vec![2]), (None, vec![1]), (Some(FOLDER), vec![4, 3]), (None, vec![5])]);
+    }
+
+    #[test]
+    fn move_position_is_after_taking_guild_out() {
+        let mut folders = sidebar();
+        Folders::place_guild(&mut folders, 1.into(), None, 2).unwrap();
+        assert_eq!(layout(&folders), [(Some(FOLDER), vec![2, 3]), (None, vec![4]), (None, vec![1]), (None, vec![5])]);
+    }
+
+    #[test]
+    fn move_only_guild_into_own_folder_keeps_it() {
+        let mut folders = GuildFolders { folders: vec![entry(Some(FOLDER), &[1]), entry(None, &[2])], ..sidebar() };
+        Folders::place_guild(&mut folders, 1.into(), Some(FOLDER), 3).unwrap();
+        assert_eq!(layout(&folders), [(Some(FOLDER), vec![1]), (None, vec![2])]);
+    }
+
+    #[test]
+    fn move_into_unknown_folder_fails() {
+        let mut folders = sidebar();
+        assert!(Folders::place_guild(&mut folders, 1.into(), Some(99), 0).is_err());
+        assert_eq!(layout(&folders), layout(&sidebar()));
+    }
+
+    #[test]
+    fn remove_keeps_guilds_in_place() {
+        let mut folders = sidebar();
+        Folders::ungroup(&mut folders, FOLDER).unwrap();
+        assert_eq!(
+            layout(&folders),
+            [(None, vec![1]), (None, vec![2]), (None, vec![3]), (None, vec![4]), (None, vec![5])]
+        );
+
+        assert!(Folders::ungroup(&mut folders, FOLDER).is_err());
+    }
+
+    #[test]
+    fn positions_follow_folders() {
+        let mut folders = sidebar();
+        create(&mut folders, &[5, 1]);
+        Folders::sync_positions(&mut folders);
+        assert_eq!(folders.guild_positions, [2, 3, 4, 5, 1]);
+    }
+}
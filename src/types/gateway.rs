@@ -1,9 +1,20 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::Error;
 
 use super::{
+    activity::Activity,
     channel::Channel,
-    guild::CachedGuild,
-    user::{BotUser, Relationship, User},
+    guild::{CachedGuild, GuildMember},
+    notifications::UserGuildSettingsEntries,
+    presence::{ClientStatus, Presence, StatusType},
+    role::Role,
+    user::{BotUser, PartialUser, Relationship, User},
+    Snowflake,
 };
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -24,4 +35,255 @@ pub struct Ready {
 
     #[serde(rename = "guilds")]
     pub cached_guilds: Vec<CachedGuild>,
+
+    pub user_settings_proto: Option<String>, // base64 PreloadedUserSettings
+    pub user_guild_settings: Option<UserGuildSettingsEntries>,
+}
+
+/// Sent right after READY to user accounts
+///
+/// `merged_members` and `merged_presences.guilds` line up with `guilds` by index.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadySupplemental {
+    pub merged_members: Vec<Vec<MergedMember>>,
+    pub merged_presences: MergedPresences,
+    pub guilds: Vec<SupplementalGuild>,
+    #[serde(default)]
+    pub lazy_private_channels: Vec<Channel>,
+}
+
+// Members are sent without their user, it's in READY's `users` instead
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MergedMember {
+    pub user_id: Snowflake,
+    #[serde(flatten)]
+    pub member: GuildMember,
+}
+
+impl MergedMember {
+    // READY's users don't include ourselves, so `me` is checked too
+    pub fn into_member(self, users: &[User], me: Option<&User>) -> GuildMember {
+        let mut member = self.member;
+        member.user = users.iter().chain(me).find(|u| u.id == self.user_id).cloned();
+        member
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MergedPresences {
+    #[serde(default)]
+    pub guilds: Vec<Vec<MergedPresence>>,
+    #[serde(default)]
+    pub friends: Vec<MergedPresence>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MergedPresence {
+    pub user_id: Snowflake,
+    pub status: StatusType,
+    #[serde(default)]
+    pub activities: Vec<Activity>,
+    pub client_status: Option<ClientStatus>,
+}
+
+impl MergedPresence {
+    pub fn into_presence(self, guild_id: Option<Snowflake>) -> Presence {
+        Presence {
+            user: PartialUser::new(self.user_id),
+            guild_id,
+            status: self.status,
+            activities: self.activities,
+            client_status: self.client_status,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SupplementalGuild {
+    pub id: Snowflake,
+}
+
+// GUILD_ROLE_CREATE and GUILD_ROLE_UPDATE
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildRoleUpdate {
+    pub guild_id: Snowflake,
+    pub role: Role,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildRoleDelete {
+    pub guild_id: Snowflake,
+    pub role_id: Snowflake,
+}
+
+// CHANNEL_DELETE and THREAD_DELETE, threads only come with their ids and parent
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChannelDelete {
+    pub id: Snowflake,
+    pub guild_id: Option<Snowflake>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildMembersChunk {
+    pub guild_id: Snowflake,
+    pub members: Vec<GuildMember>,
+    pub chunk_index: u64,
+    pub chunk_count: u64,
+    #[serde(default)]
+    pub not_found: Vec<Snowflake>,
+    #[serde(default)]
+    pub presences: Vec<Presence>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MemberQuery {
+    Query(String), // username prefix, "" for everyone
+    UserIds(Vec<Snowflake>),
+}
+
+/// Op 8, see `Websocket::request_guild_members`
+#[derive(Debug, Clone)]
+pub struct GuildMembersRequest {
+    pub guild_id: Snowflake,
+    pub query: MemberQuery,
+    pub limit: u64,
+    pub presences: bool,
+    pub nonce: String,
+    pub timeout: Duration,
+}
+
+impl GuildMembersRequest {
+    pub fn query(guild_id: Snowflake, query: &str) -> Self {
+        Self::new(guild_id, MemberQuery::Query(query.to_string()))
+    }
+
+    pub fn user_ids(guild_id: Snowflake, user_ids: Vec<Snowflake>) -> Self {
+        Self::new(guild_id, MemberQuery::UserIds(user_ids))
+    }
+
+    fn new(guild_id: Snowflake, query: MemberQuery) -> Self {
+        Self {
+            guild_id,
+            query,
+            limit: 0,
+            presences: false,
+            nonce: format!("{:016x}", fastrand::u64(..)),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    // 0 = no limit, only allowed for queries
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn presences(mut self, presences: bool) -> Self {
+        self.presences = presences;
+        self
+    }
+
+    // max 32 bytes, a random one is used otherwise
+    pub fn nonce(mut self, nonce: &str) -> Self {
+        self.nonce = nonce.to_string();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn build(&self) -> Value {
+        let mut value = json!({
+            "guild_id": self.guild_id,
+            "limit": self.limit,
+            "presences": self.presences,
+            "nonce": self.nonce,
+        });
+
+        match &self.query {
+            MemberQuery::Query(q) => value["query"] = json!(q),
+            MemberQuery::UserIds(ids) => value["user_ids"] = json!(ids),
+        }
+
+        value
+    }
+}
+
+/// Every chunk answering one `GuildMembersRequest`, put together
+#[derive(Debug, Clone, Default)]
+pub struct GuildMembers {
+    pub members: Vec<GuildMember>,
+    pub not_found: Vec<Snowflake>,
+    pub presences: Vec<Presence>,
+}
+
+/// Which live updates to get for a guild, sent with op 37 (or op 14 for a single guild)
+///
+/// `channels` maps a channel to the member-list rows (inclusive ranges of 100) to keep in sync.
+#[derive(Debug, Clone)]
+pub struct GuildSubscription {
+    pub guild_id: Snowflake,
+    pub typing: bool,
+    pub activities: bool,
+    pub threads: bool,
+    pub channels: HashMap<Snowflake, Vec<[u64; 2]>>,
+}
+
+impl GuildSubscription {
+    pub fn new(guild_id: Snowflake) -> Self {
+        Self {
+            guild_id,
+            typing: false,
+            activities: false,
+            threads: false,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn typing(mut self, typing: bool) -> Self {
+        self.typing = typing;
+        self
+    }
+
+    pub fn activities(mut self, activities: bool) -> Self {
+        self.activities = activities;
+        self
+    }
+
+    pub fn threads(mut self, threads: bool) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn channel(mut self, channel_id: Snowflake, ranges: Vec<[u64; 2]>) -> Self {
+        self.channels.insert(channel_id, ranges);
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for ranges in self.channels.values() {
+            if ranges.len() > 5 {
+                return Err(Error::InvalidApiRequest("can't subscribe to more than 5 ranges per channel".to_string()).into());
+            }
+
+            if ranges.iter().any(|[start, end]| start > end || end - start > 99) {
+                return Err(Error::InvalidApiRequest("ranges must be in order and at most 100 rows wide".to_string()).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Without guild_id, which is the key in op 37
+    pub fn build(&self) -> Value {
+        json!({
+            "typing": self.typing,
+            "activities": self.activities,
+            "threads": self.threads,
+            "channels": self.channels,
+        })
+    }
 }
@@ -4,71 +4,83 @@ use crate::types::timestamp::Timestamp;
 use crate::types::user::{AvatarDecorationData, User};
 
 use anyhow::Result;
+use bitflags::bitflags;
 use http::Method;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 use tokio::sync::MutexGuard;
 
 use super::{
     channel::{welcome_screen::WelcomeScreen, Channel},
-    common::Emoji,
+    common::{merge_fields, nullable, Emoji},
+    permissions::Permissions,
     role::Role,
     sticker::Sticker,
     Snowflake,
 };
+use super::enums::wire_enum;
+use super::flags::wire_flags;
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum MFALevel {
-    None = 0,     // dont
-    Elevated = 1, // need 2FA for moderation
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum MFALevel {
+        None = 0,     // dont
+        Elevated = 1, // need 2FA for moderation
+    }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum NSFWLevel {
-    Default = 0,
-    Explicit = 1,
-    Safe = 2,
-    AgeRestricted = 3,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum NSFWLevel {
+        Default = 0,
+        Explicit = 1,
+        Safe = 2,
+        AgeRestricted = 3,
+    }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum VerificationLevel {
-    None = 0,     // unrestricted
-    Low = 1,      // verified email
-    Medium = 2,   // registered for > 5min
-    High = 3,     // member of server > 10min
-    VeryHigh = 4, // verified phone #
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum VerificationLevel {
+        None = 0,     // unrestricted
+        Low = 1,      // verified email
+        Medium = 2,   // registered for > 5min
+        High = 3,     // member of server > 10min
+        VeryHigh = 4, // verified phone #
+    }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum MessageNotificationLevel {
-    AllMessages = 0,
-    OnlyMentions = 1,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum MessageNotificationLevel {
+        AllMessages = 0,
+        OnlyMentions = 1,
+        NoMessages = 2,    // user settings only
+        ParentDefault = 3, // user settings only, follow the guild or category
+    }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum ExplicitContentFilterLevel {
-    Disabled = 0, // no members get scanned
-    MembersWithoutRoles = 1,
-    AllMembers = 2,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum ExplicitContentFilterLevel {
+        Disabled = 0, // no members get scanned
+        MembersWithoutRoles = 1,
+        AllMembers = 2,
+    }
 }
 
-#[derive(Debug)]
-#[repr(u8)]
-pub enum SystemChannelFlags {
-    SuppressJoinNotifications = 1 << 0,
-    SuppressPremiumSubscriptions = 1 << 1,
-    SuppressGuildReminders = 1 << 2,
-    SuppressJoinReplies = 1 << 3,
-    SuppressRoleSubPurchases = 1 << 4,
-    SuppressRoleSubPurchaseReplies = 1 << 5,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct SystemChannelFlags: u64 {
+        const SUPPRESS_JOIN_NOTIFICATIONS = 1 << 0;
+        const SUPPRESS_PREMIUM_SUBSCRIPTIONS = 1 << 1;
+        const SUPPRESS_GUILD_REMINDERS = 1 << 2;
+        const SUPPRESS_JOIN_REPLIES = 1 << 3;
+        const SUPPRESS_ROLE_SUB_PURCHASES = 1 << 4;
+        const SUPPRESS_ROLE_SUB_PURCHASE_REPLIES = 1 << 5;
+    }
 }
+wire_flags!(SystemChannelFlags);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Guild {
@@ -77,17 +89,22 @@ pub struct Guild {
 
     pub owner_id: Snowflake,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon_hash: Option<String>,
 
-    #[serde(rename = "vanity_url_code")]
+    #[serde(rename = "vanity_url_code", skip_serializing_if = "Option::is_none")]
     pub vanity_invite: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub banner: Option<String>, // banner hash
 
-    #[serde(rename = "discovery_splash")]
+    #[serde(rename = "discovery_splash", skip_serializing_if = "Option::is_none")]
     pub splash_discovery: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub splash: Option<String>,
 
     #[serde(rename = "preferred_locale")]
@@ -101,34 +118,48 @@ pub struct Guild {
     #[serde(rename = "explicit_content_filter")]
     pub explicit_content_filter_level: ExplicitContentFilterLevel,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub roles: Option<Vec<Role>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub emojis: Option<Vec<Emoji>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stickers: Option<Vec<Sticker>>,
     pub features: Vec<String>,
 
     // wtf is this used for
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub application_id: Option<Snowflake>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub welcome_screen: Option<WelcomeScreen>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub public_updates_channel_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub safety_alerts_channel_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub system_channel_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rules_channel_id: Option<Snowflake>,
-    pub system_channel_flags: u8,
+    pub system_channel_flags: SystemChannelFlags,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub afk_channel_id: Option<Snowflake>,
     pub afk_timeout: i32, // in seconds
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub widget_channel_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub widget_enabled: Option<bool>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_presences: Option<u64>, // almost always null
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_members: Option<u64>,
 
     #[serde(rename = "premium_tier")]
     pub boost_tier: u8, // 0..=3
-    #[serde(rename = "premium_subscription_count")]
+    #[serde(rename = "premium_subscription_count", skip_serializing_if = "Option::is_none")]
     pub boosts: Option<u64>,
     #[serde(rename = "premium_progress_bar_enabled")]
     pub boost_bar_enabled: bool,
@@ -137,12 +168,20 @@ pub struct Guild {
     pub max_stage_video_channel_users: u64,
 
     // if using "Get current user guilds" endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub owner: Option<bool>,
-    pub permissions: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
 
     // these need "with_counts" enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub member_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub presence_count: Option<u64>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
 impl Guild {
@@ -197,7 +236,7 @@ impl Guild {
         .await
     }
 
-    /**
+    /*
      * Channels
      */
 
@@ -224,6 +263,8 @@ impl Guild {
         id: Snowflake,
         channel: Value,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_CHANNELS)?;
+
         ctx.request(
             Method::POST,
             &format!("/v9/guilds/{}/channels", id),
@@ -239,7 +280,7 @@ impl Guild {
         Self::create_channel_static(ctx, self.id, channel).await
     }
 
-    /**
+    /*
      * Members
      */
 
@@ -324,6 +365,9 @@ impl Guild {
         user_id: Snowflake,
         member: Value,
     ) -> Result<Response> {
+        let own = ctx.user.as_ref().is_some_and(|u| u.id == user_id);
+        ctx.preflight(Some(id), None, Permissions::for_member_edit(&member, own))?;
+
         ctx.request(
             Method::PATCH,
             &format!("/v9/guilds/{}/members/{}", id, user_id),
@@ -345,6 +389,8 @@ impl Guild {
         id: Snowflake,
         user_id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::KICK_MEMBERS)?;
+
         ctx.request(
             Method::DELETE,
             &format!("/v9/guilds/{}/members/{}", id, user_id),
@@ -360,7 +406,7 @@ impl Guild {
         Self::kick_member_static(ctx, self.id, user_id).await
     }
 
-    /**
+    /*
      * Bans
      */
 
@@ -369,6 +415,8 @@ impl Guild {
         id: Snowflake,
         user_id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::BAN_MEMBERS)?;
+
         ctx.request(
             Method::PUT,
             &format!("/v9/guilds/{}/bans/{}", id, user_id),
@@ -389,6 +437,8 @@ impl Guild {
         id: Snowflake,
         user_id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::BAN_MEMBERS)?;
+
         ctx.request(
             Method::DELETE,
             &format!("/v9/guilds/{}/bans/{}", id, user_id),
@@ -408,6 +458,8 @@ impl Guild {
         ctx: &mut MutexGuard<'_, Context>,
         id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::BAN_MEMBERS)?;
+
         ctx.request(Method::GET, &format!("/v9/guilds/{}/bans", id), None)
             .await
     }
@@ -420,6 +472,8 @@ impl Guild {
         id: Snowflake,
         user_id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::BAN_MEMBERS)?;
+
         ctx.request(
             Method::GET,
             &format!("/v9/guilds/{}/bans/{}", id, user_id),
@@ -435,7 +489,7 @@ impl Guild {
         Self::get_ban_static(ctx, self.id, user_id).await
     }
 
-    /**
+    /*
      * Roles
      */
 
@@ -455,6 +509,8 @@ impl Guild {
         id: Snowflake,
         role: Value,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_ROLES)?;
+
         ctx.request(
             Method::POST,
             &format!("/v9/guilds/{}/roles", id),
@@ -476,6 +532,8 @@ impl Guild {
         role_id: Snowflake,
         role: Value,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_ROLES)?;
+
         ctx.request(
             Method::PATCH,
             &format!("/v9/guilds/{}/roles/{}", id, role_id),
@@ -497,6 +555,8 @@ impl Guild {
         id: Snowflake,
         role_id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_ROLES)?;
+
         ctx.request(
             Method::DELETE,
             &format!("/v9/guilds/{}/roles/{}", id, role_id),
@@ -517,6 +577,8 @@ impl Guild {
         id: Snowflake,
         roles: Value,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_ROLES)?;
+
         ctx.request(
             Method::PATCH,
             &format!("/v9/guilds/{}/roles", id),
@@ -532,7 +594,7 @@ impl Guild {
         Self::edit_role_positions_static(ctx, self.id, roles).await
     }
 
-    /**
+    /*
      * Misc.
      */
 
@@ -540,6 +602,8 @@ impl Guild {
         ctx: &mut MutexGuard<'_, Context>,
         id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_GUILD)?;
+
         ctx.request(
             Method::GET,
             &format!("/v9/guilds/{}/invites", id),
@@ -594,6 +658,8 @@ impl Guild {
         ctx: &mut MutexGuard<'_, Context>,
         id: Snowflake,
     ) -> Result<Response> {
+        ctx.preflight(Some(id), None, Permissions::MANAGE_GUILD)?;
+
         ctx.request(
             Method::GET,
             &format!("/v9/guilds/{}/vanity-url", id),
@@ -642,6 +708,10 @@ pub struct CachedGuild {
     pub guild_scheduled_events: Vec<()>, // TODO
 
     pub version: u64,
+
+    // Filled as members come in, e.g. through `Websocket::request_guild_members`
+    #[serde(default)]
+    pub members: Vec<GuildMember>,
 }
 
 impl CachedGuild {
@@ -652,30 +722,101 @@ impl CachedGuild {
     }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug)]
-#[repr(u8)]
-pub enum GuildMemberFlags {
-    DidRejoin = 1 << 0,
-    CompletedOnboarding = 1 << 1,
-    BypassesVerification = 1 << 2,
-    StartedOnboarding = 1 << 3,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct GuildMemberFlags: u64 {
+        const DID_REJOIN = 1 << 0;
+        const COMPLETED_ONBOARDING = 1 << 1;
+        const BYPASSES_VERIFICATION = 1 << 2;
+        const STARTED_ONBOARDING = 1 << 3;
+        const IS_GUEST = 1 << 4;
+        const STARTED_HOME_ACTIONS = 1 << 5;
+        const COMPLETED_HOME_ACTIONS = 1 << 6;
+        const AUTOMOD_QUARANTINED_USERNAME = 1 << 7;
+        const DM_SETTINGS_UPSELL_ACKNOWLEDGED = 1 << 9;
+    }
 }
+wire_flags!(GuildMemberFlags);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct GuildMember {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<User>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub nick: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar: Option<String>,
     pub roles: Vec<Snowflake>,
     pub joined_at: Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub premium_since: Option<Timestamp>,
     pub deaf: bool,
     pub mute: bool,
-    pub flags: u8,
+    pub flags: GuildMemberFlags,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     pub pending: bool,
-    pub permissions: String,
+    #[serde(default, skip_serializing_if = "Permissions::is_empty")]
+    pub permissions: Permissions, // only sent with interactions
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub communication_disabled_until: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar_decoration_data: Option<AvatarDecorationData>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
+}
+
+/// A member as sent in GUILD_MEMBER_UPDATE, any field may be missing
+///
+/// Fields that can be null are `Some(None)` when they were cleared.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PartialGuildMember {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<Snowflake>, // only in gateway events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub nick: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<Snowflake>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub joined_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub premium_since: Option<Option<Timestamp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deaf: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<GuildMemberFlags>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub communication_disabled_until: Option<Option<Timestamp>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub avatar_decoration_data: Option<Option<AvatarDecorationData>>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
+}
+
+impl PartialGuildMember {
+    pub fn apply_to(&self, member: &mut GuildMember) {
+        if let Some(user) = &self.user {
+            member.user = Some(user.clone());
+        }
+
+        merge_fields!(self => member;
+            nick, avatar, roles, joined_at, premium_since, deaf, mute, flags, pending, permissions,
+            communication_disabled_until, avatar_decoration_data,
+        );
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -683,6 +824,12 @@ pub struct GuildMemberEditBuilder {
     value: Value,
 }
 
+impl Default for GuildMemberEditBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GuildMemberEditBuilder {
     pub fn new() -> Self {
         Self { value: json!({}) }
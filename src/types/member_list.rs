@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{guild::GuildMember, presence::Presence, Snowflake};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum GroupKind {
+    Role(Snowflake), // hoisted role
+    Online,
+    Offline,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ListGroup {
+    pub id: String, // role id, "online" or "offline"
+    #[serde(default)]
+    pub count: u64,
+}
+
+impl ListGroup {
+    pub fn kind(&self) -> GroupKind {
+        match self.id.as_str() {
+            "online" => GroupKind::Online,
+            "offline" => GroupKind::Offline,
+            id => id.parse().map(GroupKind::Role).unwrap_or(GroupKind::Online),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ListMember {
+    #[serde(flatten)]
+    pub member: GuildMember,
+    pub presence: Option<Presence>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ListItem {
+    Group(ListGroup),
+    Member(Box<ListMember>),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "UPPERCASE")]
+pub enum ListOp {
+    Sync { range: [u64; 2], items: Vec<ListItem> },
+    Insert { index: u64, item: ListItem },
+    Update { index: u64, item: ListItem },
+    Delete { index: u64 },
+    Invalidate { range: [u64; 2] },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildMemberListUpdate {
+    pub guild_id: Snowflake,
+    pub id: String, // "everyone" or a hash of the channel's permissions
+    pub member_count: u64,
+    pub online_count: u64,
+    pub groups: Vec<ListGroup>,
+    pub ops: Vec<ListOp>,
+}
+
+impl GuildMemberListUpdate {
+    // Members this update carries, not the whole list
+    pub fn members(&self) -> impl Iterator<Item = &ListMember> {
+        self.ops
+            .iter()
+            .flat_map(|op| match op {
+                ListOp::Sync { items, .. } => items.iter().collect(),
+                ListOp::Insert { item, .. } | ListOp::Update { item, .. } => vec![item],
+                _ => vec![],
+            })
+            .filter_map(|item| match item {
+                ListItem::Member(m) => Some(m.as_ref()),
+                _ => None,
+            })
+    }
+}
+
+/// Local copy of one member sidebar, kept in sync with GUILD_MEMBER_LIST_UPDATE
+///
+/// Rows that haven't been synced (or were invalidated) are `None`.
+#[derive(Debug, Clone)]
+pub struct MemberList {
+    pub id: String,
+    pub guild_id: Snowflake,
+    pub member_count: u64,
+    pub online_count: u64,
+    pub groups: Vec<ListGroup>,
+    pub items: Vec<Option<ListItem>>,
+}
+
+impl MemberList {
+    pub fn new(guild_id: Snowflake, id: String) -> Self {
+        Self {
+            id,
+            guild_id,
+            member_count: 0,
+            online_count: 0,
+            groups: Vec::new(),
+            items: Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, update: GuildMemberListUpdate) {
+        self.member_count = update.member_count;
+        self.online_count = update.online_count;
+        self.groups = update.groups;
+
+        for op in update.ops {
+            match op {
+                // The range is replaced as a whole, it may hold fewer items than before
+                ListOp::Sync { range: [start, end], items } => {
+                    let start = start as usize;
+                    self.grow_to((end as usize + 1).max(start + items.len()));
+
+                    for item in self.items.iter_mut().take(end as usize + 1).skip(start) {
+                        *item = None;
+                    }
+                    for (i, item) in items.into_iter().enumerate() {
+                        self.items[start + i] = Some(item);
+                    }
+                }
+                ListOp::Invalidate { range: [start, end] } => {
+                    let end = (end as usize + 1).min(self.items.len());
+                    for item in self.items.iter_mut().take(end).skip(start as usize) {
+                        *item = None;
+                    }
+                }
+                ListOp::Insert { index, item } => {
+                    let index = index as usize;
+                    self.grow_to(index);
+                    self.items.insert(index, Some(item));
+                }
+                ListOp::Update { index, item } => {
+                    let index = index as usize;
+                    self.grow_to(index + 1);
+                    self.items[index] = Some(item);
+                }
+                ListOp::Delete { index } => {
+                    if (index as usize) < self.items.len() {
+                        self.items.remove(index as usize);
+                    }
+                }
+            }
+        }
+    }
+
+    fn grow_to(&mut self, len: usize) {
+        if self.items.len() < len {
+            self.items.resize(len, None);
+        }
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &ListMember> {
+        self.items.iter().filter_map(|item| match item {
+            Some(ListItem::Member(m)) => Some(m.as_ref()),
+            _ => None,
+        })
+    }
+
+    /// Synced members under the group header they appear below, in sidebar order
+    pub fn sections(&self) -> Vec<(&ListGroup, Vec<&ListMember>)> {
+        let mut sections: Vec<(&ListGroup, Vec<&ListMember>)> = Vec::new();
+
+        for item in self.items.iter().flatten() {
+            match item {
+                ListItem::Group(g) => sections.push((g, Vec::new())),
+                ListItem::Member(m) => {
+                    if let Some((_, members)) = sections.last_mut() {
+                        members.push(m);
+                    }
+                }
+            }
+        }
+
+        sections
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct MemberLists(HashMap<(Snowflake, String), MemberList>);
+
+impl MemberLists {
+    pub fn get(&self, guild_id: Snowflake, id: &str) -> Option<&MemberList> {
+        self.0.get(&(guild_id, id.to_string()))
+    }
+
+    pub fn for_guild(&self, guild_id: Snowflake) -> impl Iterator<Item = &MemberList> {
+        self.0.values().filter(move |l| l.guild_id == guild_id)
+    }
+
+    pub fn apply(&mut self, update: GuildMemberListUpdate) -> &MemberList {
+        let list = self
+            .0
+            .entry((update.guild_id, update.id.clone()))
+            .or_insert_with(|| MemberList::new(update.guild_id, update.id.clone()));
+
+        list.apply(update);
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn group(id: &str) -> Value {
+        json!({ "group": { "id": id, "count": 1 } })
+    }
+
+    fn member(id: u64) -> Value {
+        json!({ "member": {
+            "user": { "id": id.to_string(), "username": format!("user{id}") },
+            "roles": [],
+            "joined_at": "2024-01-01T00:00:00+00:00",
+            "deaf": false,
+            "mute": false,
+            "flags": 0,
+        }})
+    }
+
+    fn update(ops: Value) -> GuildMemberListUpdate {
+        serde_json::from_value(json!({
+            "guild_id": "1",
+            "id": "everyone",
+            "member_count": 10,
+            "online_count": 5,
+            "groups": [{ "id": "online", "count": 5 }],
+            "ops": ops,
+        }))
+        .unwrap()
+    }
+
+    // "online"/"offline" for group headers, the user id for members, "-" for unsynced rows
+    fn rows(list: &MemberList) -> Vec<String> {
+        list.items
+            .iter()
+            .map(|item| match item {
+                Some(ListItem::Group(g)) => g.id.clone(),
+                Some(ListItem::Member(m)) => m.member.user.as_ref().unwrap().id.to_string(),
+                None => "-".to_string(),
+            })
+            .collect()
+    }
+
+    fn synced() -> MemberList {
+        let mut list = MemberList::new(Snowflake::from(1), "everyone".to_string());
+        list.apply(update(json!([
+            { "op": "SYNC", "range": [0, 3], "items": [group("online"), member(10), member(11), member(12)] },
+        ])));
+        list
+    }
+
+    #[test]
+    fn group_kind() {
+        let kind = |id: &str| ListGroup { id: id.to_string(), count: 0 }.kind();
+
+        assert_eq!(kind("online"), GroupKind::Online);
+        assert_eq!(kind("offline"), GroupKind::Offline);
+        assert_eq!(kind("123"), GroupKind::Role(Snowflake::from(123)));
+    }
+
+    #[test]
+    fn sync() {
+        let list = synced();
+        assert_eq!(rows(&list), ["online", "10", "11", "12"]);
+        assert_eq!((list.member_count, list.online_count), (10, 5));
+    }
+
+    #[test]
+    fn sync_clears_range() {
+        let mut list = synced();
+        list.apply(update(json!([
+            { "op": "SYNC", "range": [0, 3], "items": [group("online"), member(20)] },
+        ])));
+
+        assert_eq!(rows(&list), ["online", "20", "-", "-"]);
+    }
+
+    #[test]
+    fn insert_update_delete() {
+        let mut list = synced();
+        list.apply(update(json!([
+            { "op": "INSERT", "index": 2, "item": member(13) },
+            { "op": "UPDATE", "index": 1, "item": member(14) },
+            { "op": "DELETE", "index": 3 },
+        ])));
+
+        assert_eq!(rows(&list), ["online", "14", "13", "12"]);
+    }
+
+    #[test]
+    fn insert_past_end() {
+        let mut list = synced();
+        list.apply(update(json!([{ "op": "INSERT", "index": 6, "item": member(13) }])));
+
+        assert_eq!(rows(&list), ["online", "10", "11", "12", "-", "-", "13"]);
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut list = synced();
+        list.apply(update(json!([{ "op": "INVALIDATE", "range": [1, 2] }])));
+
+        assert_eq!(rows(&list), ["online", "-", "-", "12"]);
+        assert_eq!(list.members().count(), 1);
+    }
+
+    #[test]
+    fn sections() {
+        let mut list = synced();
+        list.apply(update(json!([{ "op": "INSERT", "index": 3, "item": group("offline") }])));
+
+        let sections: Vec<(GroupKind, usize)> = list.sections().iter().map(|(g, m)| (g.kind(), m.len())).collect();
+        assert_eq!(sections, [(GroupKind::Online, 2), (GroupKind::Offline, 1)]);
+    }
+}
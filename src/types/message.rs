@@ -1,24 +1,33 @@
+use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use crate::types::message::embed::Embed;
 use crate::types::timestamp::Timestamp;
 use crate::types::{
     channel::{Channel, ChannelMention},
-    common::{Emoji, Resolved},
+    common::{merge_fields, nullable, Emoji, Resolved},
     poll::Poll,
     role::RoleSubscriptionData,
     sticker::{Sticker, StickerItem},
     user::User,
     Snowflake,
 };
-
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum AttachmentFlags {
-    IsRemix = 1 << 2,
+use super::enums::wire_enum;
+use super::flags::wire_flags;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct AttachmentFlags: u64 {
+        const IS_CLIP = 1 << 0;
+        const IS_THUMBNAIL = 1 << 1;
+        const IS_REMIX = 1 << 2;
+        const IS_SPOILER = 1 << 3;
+        const CONTAINS_EXPLICIT_MEDIA = 1 << 4;
+        const IS_ANIMATED = 1 << 5;
+    }
 }
+wire_flags!(AttachmentFlags);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Attachment {
@@ -26,7 +35,9 @@ pub struct Attachment {
 
     pub filename: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub content_type: Option<String>, // MIME type
 
     // Size in bytes
@@ -35,15 +46,25 @@ pub struct Attachment {
     pub proxy_url: String,
 
     // Image
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub width: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub height: Option<usize>,
 
     // Voice message
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub duration_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub waveform: Option<String>, // base64 encoded bytearray
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ephemeral: Option<bool>,
-    pub flags: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<AttachmentFlags>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -62,68 +83,70 @@ pub struct Reaction {
     pub burst_colors: Vec<u32>,
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u64)]
-pub enum MessageType {
-    Default = 0,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum MessageType {
+        Default = 0,
 
-    RecipientAdd = 1,
-    RecipientRemove = 2,
+        RecipientAdd = 1,
+        RecipientRemove = 2,
 
-    Call = 3,
+        Call = 3,
 
-    NameChange = 4,
-    IconChange = 5,
-    PinnedMessage = 6,
+        NameChange = 4,
+        IconChange = 5,
+        PinnedMessage = 6,
 
-    UserJoin = 7,
+        UserJoin = 7,
 
-    GuildBoost = 8,
-    GuildBoostTier1 = 9,
-    GuildBoostTier2 = 10,
-    GuildBoostTier3 = 11,
+        GuildBoost = 8,
+        GuildBoostTier1 = 9,
+        GuildBoostTier2 = 10,
+        GuildBoostTier3 = 11,
 
-    FollowAdd = 12,
+        FollowAdd = 12,
 
-    GuildDiscoveryDisqualified = 14,
-    GuildDiscoveryRequalified = 15,
-    GuildDiscoveryGracePeriodInitialWarning = 16,
-    GuildDiscoveryGracePeriodFinalWarning = 17,
+        GuildDiscoveryDisqualified = 14,
+        GuildDiscoveryRequalified = 15,
+        GuildDiscoveryGracePeriodInitialWarning = 16,
+        GuildDiscoveryGracePeriodFinalWarning = 17,
 
-    ThreadCreated = 18,
-    ThreadStarterMessage = 21,
+        ThreadCreated = 18,
+        ThreadStarterMessage = 21,
 
-    Reply = 19,
-    ChatInputCommand = 20,
+        Reply = 19,
+        ChatInputCommand = 20,
 
-    GuildInviteReminder = 22,
+        GuildInviteReminder = 22,
 
-    ContextMenuCommand = 23,
-    AutoModerationAction = 24,
-    RoleSubscriptionPurchase = 25,
-    InteractionPremiumUpsell = 26,
+        ContextMenuCommand = 23,
+        AutoModerationAction = 24,
+        RoleSubscriptionPurchase = 25,
+        InteractionPremiumUpsell = 26,
 
-    StageStart = 27,
-    StageEnd = 28,
-    StageSpeaker = 29,
-    StageTopic = 31,
+        StageStart = 27,
+        StageEnd = 28,
+        StageSpeaker = 29,
+        StageTopic = 31,
 
-    GuildApplicationPremiumSubscription = 32,
-    PurchaseNotification = 44,
+        GuildApplicationPremiumSubscription = 32,
+        PurchaseNotification = 44,
 
-    GuildIncidentAlertModeEnabled = 36,
-    GuildIncidentAlertModeDisabled = 37,
-    GuildIncidentReportRaid = 38,
-    GuildIncidentReportFalseAlarm = 39,
+        GuildIncidentAlertModeEnabled = 36,
+        GuildIncidentAlertModeDisabled = 37,
+        GuildIncidentReportRaid = 38,
+        GuildIncidentReportFalseAlarm = 39,
+    }
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum ActivityType {
-    Join = 1,
-    Spectate = 2,
-    Listen = 3,
-    JoinRequest = 5,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum ActivityType {
+        Join = 1,
+        Spectate = 2,
+        Listen = 3,
+        JoinRequest = 5,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -134,36 +157,49 @@ pub struct Activity {
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Reference {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub message_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub channel_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub guild_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub fail_if_not_exists: Option<bool>,
-}
 
-#[derive(Deserialize_repr, Serialize_repr, Debug)]
-#[repr(u16)]
-pub enum MessageFlag {
-    Crossposted = 1 << 0,
-    IsCrosspost = 1 << 1,
-    SuppressEmbeds = 1 << 2,
-    SourceMessageDeleted = 1 << 3,
-    Urgent = 1 << 4,
-    HasThread = 1 << 5,
-    Ephemeral = 1 << 6,
-    Loading = 1 << 7,
-    FailedToMentionSomeRolesInThread = 1 << 8,
-    SuppressNotifications = 1 << 12,
-    IsVoiceMessage = 1 << 13,
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum InteractionType {
-    Ping = 1,
-    ApplicationCommand = 2,
-    MessageComponent = 3,
-    ApplicationCommandAutocomplete = 4,
-    ModalSubmit = 5,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct MessageFlag: u64 {
+        const CROSSPOSTED = 1 << 0;
+        const IS_CROSSPOST = 1 << 1;
+        const SUPPRESS_EMBEDS = 1 << 2;
+        const SOURCE_MESSAGE_DELETED = 1 << 3;
+        const URGENT = 1 << 4;
+        const HAS_THREAD = 1 << 5;
+        const EPHEMERAL = 1 << 6;
+        const LOADING = 1 << 7;
+        const FAILED_TO_MENTION_SOME_ROLES_IN_THREAD = 1 << 8;
+        const SUPPRESS_NOTIFICATIONS = 1 << 12;
+        const IS_VOICE_MESSAGE = 1 << 13;
+        const HAS_SNAPSHOT = 1 << 14;
+        const IS_COMPONENTS_V2 = 1 << 15;
+    }
+}
+wire_flags!(MessageFlag);
+
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum InteractionType {
+        Ping = 1,
+        ApplicationCommand = 2,
+        MessageComponent = 3,
+        ApplicationCommandAutocomplete = 4,
+        ModalSubmit = 5,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -188,10 +224,12 @@ pub struct Call {
 pub struct Message {
     pub id: Snowflake,
     pub channel_id: Snowflake,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub guild_id: Option<Snowflake>,
 
     pub content: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub author: Option<User>,
 
     pub timestamp: Timestamp,
@@ -201,46 +239,182 @@ pub struct Message {
 
     pub mentions: Vec<User>,
     pub mention_roles: Vec<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mention_channels: Option<ChannelMention>,
     pub mention_everyone: bool,
 
     pub attachments: Vec<Attachment>,
     pub embeds: Vec<Embed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub reactions: Option<Vec<Reaction>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub nonce: Option<String>, // String "or integer"
 
     pub pinned: bool,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub webhook_id: Option<Snowflake>,
 
     #[serde(rename = "type")]
     pub message_type: MessageType,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub activity: Option<Activity>,
 
     #[serde(skip)]
     // TODO: Application type
     pub application: Option<()>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub interaction_metadata: Option<InteractionMetadata>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub application_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub message_reference: Option<Reference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub referenced_message: Option<Box<Message>>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub thread: Option<Channel>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub components: Option<Vec<component::Component>>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sticker_items: Option<Vec<StickerItem>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stickers: Option<Vec<Sticker>>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub position: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub role_subscription_data: Option<RoleSubscriptionData>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub resolved: Option<Resolved>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub poll: Option<Poll>,
 
-    pub flags: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<MessageFlag>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
+}
+
+/// A message as sent in MESSAGE_UPDATE, only `id` and `channel_id` are guaranteed
+///
+/// Embed unfurls for example only carry the new `embeds`. Fields that can be null are
+/// `Some(None)` when they were cleared.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PartialMessage {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<Snowflake>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<User>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub edited_timestamp: Option<Option<Timestamp>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tts: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mentions: Option<Vec<User>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mention_roles: Option<Vec<Snowflake>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub mention_channels: Option<Option<ChannelMention>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mention_everyone: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<Vec<Attachment>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embeds: Option<Vec<Embed>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<Option<Vec<Reaction>>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Option<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned: Option<bool>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub webhook_id: Option<Option<Snowflake>>,
+
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<MessageType>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub activity: Option<Option<Activity>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub interaction_metadata: Option<Option<InteractionMetadata>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub application_id: Option<Option<Snowflake>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub message_reference: Option<Option<Reference>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub referenced_message: Option<Option<Box<Message>>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub thread: Option<Option<Channel>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub components: Option<Option<Vec<component::Component>>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub sticker_items: Option<Option<Vec<StickerItem>>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub stickers: Option<Option<Vec<Sticker>>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub position: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub role_subscription_data: Option<Option<RoleSubscriptionData>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<Option<Resolved>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Option<Poll>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Option<MessageFlag>>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
+}
+
+impl PartialMessage {
+    /// Merges the fields that were sent into `message`, which should be the same message
+    pub fn apply_to(&self, message: &mut Message) {
+        if let Some(guild_id) = self.guild_id {
+            message.guild_id = Some(guild_id);
+        }
+        if let Some(author) = &self.author {
+            message.author = Some(author.clone());
+        }
+
+        merge_fields!(self => message;
+            content, timestamp, edited_timestamp, tts, mentions, mention_roles, mention_channels,
+            mention_everyone, attachments, embeds, reactions, nonce, pinned, webhook_id, message_type,
+            activity, interaction_metadata, application_id, message_reference, referenced_message,
+            thread, components, sticker_items, stickers, position, role_subscription_data, resolved,
+            poll, flags,
+        );
+    }
 }
 
 pub mod embed {
@@ -251,8 +425,9 @@ pub mod embed {
     pub struct Footer {
         pub text: String, // 2048 chars
 
-        #[serde(rename = "proxy_icon_url")]
+        #[serde(rename = "proxy_icon_url", skip_serializing_if = "Option::is_none")]
         pub icon_proxy_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub icon_url: Option<String>,
     }
 
@@ -279,33 +454,45 @@ pub mod embed {
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct Image {
         pub url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub proxy_url: Option<String>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub width: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub height: Option<usize>,
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct Thumbnail {
         pub url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub proxy_url: Option<String>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub width: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub height: Option<usize>,
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct Video {
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub proxy_url: Option<String>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub width: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub height: Option<usize>,
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct Provider {
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub url: Option<String>,
     }
 
@@ -326,10 +513,12 @@ pub mod embed {
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct Author {
         pub name: String, // 256 chars
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub url: Option<String>,
 
-        #[serde(rename = "proxy_icon_url")]
+        #[serde(rename = "proxy_icon_url", skip_serializing_if = "Option::is_none")]
         pub icon_proxy_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub icon_url: Option<String>,
     }
 
@@ -363,6 +552,7 @@ pub mod embed {
     pub struct Field {
         pub name: String,  // 256 chars
         pub value: String, // 1024 chars
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub inline: Option<bool>,
     }
 
@@ -384,45 +574,62 @@ pub mod embed {
     // Max length of all text cannot exceed 6000 chars
     #[derive(Deserialize, Serialize, Debug, Clone)]
     pub struct Embed {
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub title: Option<String>, // 256 chars
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub description: Option<String>, // 4096 chars
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub url: Option<String>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub timestamp: Option<Timestamp>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub color: Option<u32>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub footer: Option<Footer>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub image: Option<Image>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub thumbnail: Option<Thumbnail>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub video: Option<Video>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub provider: Option<Provider>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub author: Option<Author>,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub fields: Option<Vec<Field>>, // 25 max
+
+        #[cfg(feature = "extra-fields")]
+        #[serde(flatten)]
+        pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
     }
 }
 
 pub mod component {
     use serde::{Deserialize, Serialize};
     use serde_json::{json, Value};
-    use serde_repr::{Deserialize_repr, Serialize_repr};
 
     use crate::prelude::Snowflake;
     use crate::types::channel::ChannelType;
     use crate::types::common::Emoji;
-
-    #[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-    #[repr(u8)]
-    pub enum ComponentType {
-        ActionRow = 1,
-        Button = 2,
-        StringSelect = 3,
-        TextInput = 4,
-        UserSelect = 5,
-        RoleSelect = 6,
-        MentionableSelect = 7,
-        ChannelSelect = 8,
+    use crate::types::enums::{wire_enum, wire_str_enum};
+
+    wire_enum! {
+        #[derive(Debug, Eq, PartialEq, Clone)]
+        pub enum ComponentType {
+            ActionRow = 1,
+            Button = 2,
+            StringSelect = 3,
+            TextInput = 4,
+            UserSelect = 5,
+            RoleSelect = 6,
+            MentionableSelect = 7,
+            ChannelSelect = 8,
+        }
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -432,6 +639,12 @@ pub mod component {
         pub components: Option<Vec<Value>>,
     }
 
+    impl Default for ActionRow {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl ActionRow {
         pub fn new() -> Self {
             Self {
@@ -453,14 +666,15 @@ pub mod component {
         }
     }
 
-    #[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-    #[repr(u8)]
-    pub enum ButtonStyle {
-        Primary = 1,
-        Secondary = 2,
-        Success = 3,
-        Danger = 4,
-        Link = 5,
+    wire_enum! {
+        #[derive(Debug, Eq, PartialEq, Clone)]
+        pub enum ButtonStyle {
+            Primary = 1,
+            Secondary = 2,
+            Success = 3,
+            Danger = 4,
+            Link = 5,
+        }
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -518,12 +732,13 @@ pub mod component {
         pub default: Option<bool>,
     }
 
-    #[derive(Deserialize, Serialize, Debug, Clone)]
-    #[serde(rename_all = "lowercase")]
-    pub enum DefaultValueType {
-        User,
-        Role,
-        Channel,
+    wire_str_enum! {
+        #[derive(Debug, Clone)]
+        pub enum DefaultValueType {
+            User = "user",
+            Role = "role",
+            Channel = "channel",
+        }
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -653,11 +868,12 @@ pub mod component {
         }
     }
 
-    #[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-    #[repr(u8)]
-    pub enum TextInputStyle {
-        Short = 1,
-        Paragraph = 2,
+    wire_enum! {
+        #[derive(Debug, Eq, PartialEq, Clone)]
+        pub enum TextInputStyle {
+            Short = 1,
+            Paragraph = 2,
+        }
     }
 
     #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -752,6 +968,12 @@ pub struct EmbedBuilder {
     value: Value,
 }
 
+impl Default for EmbedBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EmbedBuilder {
     pub fn new() -> Self {
         Self { value: json!({}) }
@@ -945,3 +1167,142 @@ impl MessageBuilder {
         self.value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::common::assert_round_trips;
+
+    // A reply with an attachment and a link embed, as MESSAGE_CREATE sends it minus `member`
+    fn captured() -> Value {
+        json!({
+            "id": "1235171046398578749",
+            "channel_id": "1097941239282155521",
+            "guild_id": "1097941238770434088",
+            "type": 19,
+            "content": "see https://example.com",
+            "author": {
+                "id": "1097940928617857095",
+                "username": "someone",
+                "global_name": "Someone",
+                "avatar": "a_0123456789abcdef0123456789abcdef",
+                "avatar_decoration_data": null,
+                "discriminator": "0",
+                "public_flags": 64,
+                "clan": null,
+            },
+            "timestamp": "2024-05-01T12:34:56.789000+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [{
+                "id": "1097940928617857096",
+                "username": "other",
+                "global_name": null,
+                "avatar": null,
+                "avatar_decoration_data": null,
+                "discriminator": "0",
+                "public_flags": 0,
+            }],
+            "mention_roles": [],
+            "attachments": [{
+                "id": "1235171046117560390",
+                "filename": "image.png",
+                "size": 12345,
+                "url": "https://cdn.discordapp.com/attachments/1/2/image.png",
+                "proxy_url": "https://media.discordapp.net/attachments/1/2/image.png",
+                "width": 640,
+                "height": 480,
+                "content_type": "image/png",
+                "placeholder": "abc",
+                "placeholder_version": 1,
+            }],
+            "embeds": [{
+                "type": "link",
+                "url": "https://example.com",
+                "title": "Example Domain",
+                "description": "This domain is for use in examples.",
+                "provider": { "name": "Example" },
+                "thumbnail": {
+                    "url": "https://example.com/thumb.png",
+                    "proxy_url": "https://images-ext-1.discordapp.net/external/thumb.png",
+                    "width": 128,
+                    "height": 128,
+                },
+                "content_scan_version": 1,
+            }],
+            "pinned": false,
+            "flags": 0,
+            "components": [],
+            "nonce": "1235171044494090240",
+            "message_reference": {
+                "type": 0,
+                "channel_id": "1097941239282155521",
+                "message_id": "1235170000000000000",
+                "guild_id": "1097941238770434088",
+            },
+        })
+    }
+
+    fn update(data: Value) -> PartialMessage {
+        let mut partial = json!({ "id": "1235171046398578749", "channel_id": "1097941239282155521" });
+        partial.as_object_mut().unwrap().extend(data.as_object().unwrap().clone());
+        serde_json::from_value(partial).unwrap()
+    }
+
+    #[test]
+    fn embed_unfurl_only_replaces_embeds() {
+        let mut message: Message = serde_json::from_value(captured()).unwrap();
+        update(json!({ "embeds": [] })).apply_to(&mut message);
+
+        assert!(message.embeds.is_empty());
+        assert_eq!(message.content, "see https://example.com");
+        assert_eq!(message.attachments.len(), 1);
+        assert!(message.message_reference.is_some());
+        assert!(message.edited_timestamp.is_none());
+    }
+
+    #[test]
+    fn edits_overwrite_sent_fields() {
+        let mut message: Message = serde_json::from_value(captured()).unwrap();
+        update(json!({
+            "content": "edited",
+            "edited_timestamp": "2024-05-01T12:40:00.000000+00:00",
+            "flags": 4,
+            "author": { "id": "1097940928617857095", "username": "renamed" },
+        }))
+        .apply_to(&mut message);
+
+        assert_eq!(message.content, "edited");
+        assert_eq!(message.edited_timestamp.unwrap().to_string(), "2024-05-01T12:40:00+00:00");
+        assert_eq!(message.flags, Some(MessageFlag::SUPPRESS_EMBEDS));
+        assert_eq!(message.author.unwrap().username, "renamed");
+        assert_eq!(message.guild_id, Some(1097941238770434088.into()));
+    }
+
+    #[test]
+    fn null_clears_nullable_fields() {
+        let mut message: Message = serde_json::from_value(captured()).unwrap();
+        update(json!({ "edited_timestamp": "2024-05-01T12:40:00.000000+00:00" })).apply_to(&mut message);
+        update(json!({
+            "edited_timestamp": null,
+            "message_reference": null,
+            "nonce": null,
+            "components": null,
+            "flags": null,
+        }))
+        .apply_to(&mut message);
+
+        assert!(message.edited_timestamp.is_none());
+        assert!(message.message_reference.is_none());
+        assert!(message.nonce.is_none());
+        assert!(message.components.is_none());
+        assert!(message.flags.is_none());
+        assert_eq!(message.embeds.len(), 1);
+    }
+
+    #[test]
+    fn captured_message_round_trips() {
+        assert_round_trips::<Message>(captured());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use chrono::Utc;
+use http::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::MutexGuard;
+
+use crate::context::Context;
+
+use super::{guild::MessageNotificationLevel, timestamp::Timestamp, Snowflake};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MuteConfig {
+    pub selected_time_window: Option<i64>, // seconds, -1 = until unmuted
+    pub end_time: Option<Timestamp>,
+}
+
+impl MuteConfig {
+    pub fn expired(&self) -> bool {
+        self.end_time.as_ref().is_some_and(|t| t.0 <= Utc::now())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChannelOverride {
+    pub channel_id: Snowflake,
+    pub muted: bool,
+    pub mute_config: Option<MuteConfig>,
+    pub message_notifications: MessageNotificationLevel,
+    #[serde(default)]
+    pub collapsed: bool,
+}
+
+impl ChannelOverride {
+    pub fn is_muted(&self) -> bool {
+        self.muted && !self.mute_config.as_ref().is_some_and(|c| c.expired())
+    }
+}
+
+/// Notification settings for one guild, `guild_id` is None for DMs
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserGuildSettings {
+    pub guild_id: Option<Snowflake>,
+    pub muted: bool,
+    pub mute_config: Option<MuteConfig>,
+    pub message_notifications: MessageNotificationLevel,
+    pub suppress_everyone: bool,
+    pub suppress_roles: bool,
+    pub mobile_push: bool,
+    #[serde(default)]
+    pub hide_muted_channels: bool,
+    #[serde(default)]
+    pub mute_scheduled_events: bool,
+    #[serde(default)]
+    pub channel_overrides: Vec<ChannelOverride>,
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl UserGuildSettings {
+    // Timed mutes stay `muted` until discord notices they ran out
+    pub fn is_muted(&self) -> bool {
+        self.muted && !self.mute_config.as_ref().is_some_and(|c| c.expired())
+    }
+
+    pub fn channel(&self, channel_id: Snowflake) -> Option<&ChannelOverride> {
+        self.channel_overrides.iter().find(|c| c.channel_id == channel_id)
+    }
+}
+
+// READY's `user_guild_settings`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserGuildSettingsEntries {
+    pub entries: Vec<UserGuildSettings>,
+    #[serde(default)]
+    pub partial: bool,
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Changes to a guild's or channel's notification settings
+///
+/// `suppress_*` and `mobile_push` only apply to guilds.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NotificationSettingsBuilder {
+    value: Value,
+}
+
+impl Default for NotificationSettingsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NotificationSettingsBuilder {
+    pub fn new() -> Self {
+        Self { value: json!({}) }
+    }
+
+    pub fn set_muted(mut self, muted: bool) -> Self {
+        self.value["muted"] = json!(muted);
+        self.value["mute_config"] = json!({ "selected_time_window": -1, "end_time": null });
+        self
+    }
+
+    pub fn set_muted_for(mut self, duration: Duration) -> Self {
+        let end_time = Utc::now() + duration;
+        self.value["muted"] = json!(true);
+        self.value["mute_config"] = json!({
+            "selected_time_window": duration.as_secs(),
+            "end_time": end_time.to_rfc3339(),
+        });
+        self
+    }
+
+    pub fn set_notifications(mut self, level: MessageNotificationLevel) -> Self {
+        self.value["message_notifications"] = json!(level);
+        self
+    }
+
+    pub fn set_suppress_everyone(mut self, suppress: bool) -> Self {
+        self.value["suppress_everyone"] = json!(suppress);
+        self
+    }
+
+    pub fn set_suppress_roles(mut self, suppress: bool) -> Self {
+        self.value["suppress_roles"] = json!(suppress);
+        self
+    }
+
+    pub fn set_mobile_push(mut self, mobile_push: bool) -> Self {
+        self.value["mobile_push"] = json!(mobile_push);
+        self
+    }
+
+    pub fn build(self) -> Value {
+        self.value
+    }
+}
+
+/// Per-guild and per-channel notification settings of the logged in account
+///
+/// Settings arrive with READY and are kept up to date by USER_GUILD_SETTINGS_UPDATE,
+/// changes are sent to `/users/@me/guilds/{guild}/settings`.
+pub struct NotificationSettings;
+
+impl NotificationSettings {
+    pub fn get(ctx: &Context, guild_id: Option<Snowflake>) -> Option<&UserGuildSettings> {
+        ctx.cache.guild_settings.get(&guild_id)
+    }
+
+    pub fn channel(ctx: &Context, guild_id: Option<Snowflake>, channel_id: Snowflake) -> Option<&ChannelOverride> {
+        Self::get(ctx, guild_id)?.channel(channel_id)
+    }
+
+    pub async fn update_guild(
+        ctx: &mut MutexGuard<'_, Context>,
+        guild_id: Option<Snowflake>,
+        settings: NotificationSettingsBuilder,
+    ) -> Result<UserGuildSettings> {
+        Self::patch(ctx, guild_id, settings.build()).await
+    }
+
+    pub async fn update_channel(
+        ctx: &mut MutexGuard<'_, Context>,
+        guild_id: Option<Snowflake>,
+        channel_id: Snowflake,
+        settings: NotificationSettingsBuilder,
+    ) -> Result<UserGuildSettings> {
+        Self::patch(ctx, guild_id, Self::channel_body(channel_id, settings)).await
+    }
+
+    fn channel_body(channel_id: Snowflake, settings: NotificationSettingsBuilder) -> Value {
+        let overrides = HashMap::from([(channel_id, settings.build())]);
+        json!({ "channel_overrides": overrides })
+    }
+
+    async fn patch(
+        ctx: &mut MutexGuard<'_, Context>,
+        guild_id: Option<Snowflake>,
+        body: Value,
+    ) -> Result<UserGuildSettings> {
+        let guild = guild_id.map(|g| g.to_string()).unwrap_or("@me".to_string());
+        let resp = ctx
+            .request(Method::PATCH, &format!("/v9/users/@me/guilds/{guild}/settings"), Some(body))
+            .await?
+            .error_for_status()?;
+
+        let settings: UserGuildSettings = serde_json::from_value(resp.body)?;
+        ctx.cache.add_guild_settings(settings.clone());
+
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(guild_id: u64, muted: bool, end_time: Option<&str>) -> UserGuildSettings {
+        serde_json::from_value(json!({
+            "guild_id": guild_id.to_string(),
+            "muted": muted,
+            "mute_config": { "selected_time_window": 3600, "end_time": end_time },
+            "message_notifications": 1,
+            "suppress_everyone": false,
+            "suppress_roles": false,
+            "mobile_push": true,
+            "channel_overrides": [{
+                "channel_id": "20", "muted": true, "mute_config": null,
+                "message_notifications": 3, "collapsed": false,
+            }],
+            "version": 5,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn timed_mute_sets_end_time() {
+        let body = NotificationSettingsBuilder::new()
+            .set_muted_for(Duration::from_secs(3600))
+            .set_notifications(MessageNotificationLevel::OnlyMentions)
+            .build();
+
+        assert_eq!(body["muted"], true);
+        assert_eq!(body["message_notifications"], 1);
+
+        let config: MuteConfig = serde_json::from_value(body["mute_config"].clone()).unwrap();
+        assert_eq!(config.selected_time_window, Some(3600));
+        let left = config.end_time.as_ref().unwrap().0.signed_duration_since(Utc::now()).num_seconds();
+        assert!((3590..=3600).contains(&left), "{left}");
+        assert!(!config.expired());
+    }
+
+    #[test]
+    fn unmute_clears_mute_config() {
+        let body = NotificationSettingsBuilder::new().set_muted(false).build();
+        assert_eq!(body, json!({
+            "muted": false,
+            "mute_config": { "selected_time_window": -1, "end_time": null },
+        }));
+    }
+
+    #[test]
+    fn channel_settings_are_sent_as_overrides() {
+        let settings = NotificationSettingsBuilder::new()
+            .set_muted(true)
+            .set_notifications(MessageNotificationLevel::NoMessages);
+
+        let body = NotificationSettings::channel_body(20.into(), settings);
+        assert_eq!(body, json!({
+            "channel_overrides": {
+                "20": {
+                    "muted": true,
+                    "mute_config": { "selected_time_window": -1, "end_time": null },
+                    "message_notifications": 2,
+                },
+            },
+        }));
+    }
+
+    #[test]
+    fn update_replaces_cached_settings() {
+        let mut ctx = Context::default();
+        ctx.cache.add_guild_settings(settings(1, false, None));
+        assert!(!NotificationSettings::get(&ctx, Some(1.into())).unwrap().is_muted());
+
+        // USER_GUILD_SETTINGS_UPDATE carries the whole settings of one guild
+        ctx.cache.add_guild_settings(settings(1, true, None));
+
+        assert_eq!(ctx.cache.guild_settings.len(), 1);
+        assert!(NotificationSettings::get(&ctx, Some(1.into())).unwrap().is_muted());
+
+        let channel = NotificationSettings::channel(&ctx, Some(1.into()), 20.into()).unwrap();
+        assert!(channel.is_muted());
+        assert_eq!(channel.message_notifications, MessageNotificationLevel::ParentDefault);
+        assert!(NotificationSettings::channel(&ctx, Some(1.into()), 21.into()).is_none());
+    }
+
+    #[test]
+    fn expired_mute_is_not_muted() {
+        let settings = settings(1, true, Some("2020-01-01T00:00:00+00:00"));
+        assert!(!settings.is_muted());
+    }
+}
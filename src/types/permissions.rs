@@ -0,0 +1,410 @@
+use std::fmt::Display;
+
+use bitflags::bitflags;
+use chrono::Utc;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use super::{
+    channel::{Channel, OverwriteType, PermissionOverwrite},
+    guild::{CachedGuild, GuildMember},
+    Snowflake,
+};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct Permissions: u64 {
+        const CREATE_INSTANT_INVITE = 1 << 0;
+        const KICK_MEMBERS = 1 << 1;
+        const BAN_MEMBERS = 1 << 2;
+        const ADMINISTRATOR = 1 << 3;
+        const MANAGE_CHANNELS = 1 << 4;
+        const MANAGE_GUILD = 1 << 5;
+        const ADD_REACTIONS = 1 << 6;
+        const VIEW_AUDIT_LOG = 1 << 7;
+        const PRIORITY_SPEAKER = 1 << 8;
+        const STREAM = 1 << 9;
+        const VIEW_CHANNEL = 1 << 10;
+        const SEND_MESSAGES = 1 << 11;
+        const SEND_TTS_MESSAGES = 1 << 12;
+        const MANAGE_MESSAGES = 1 << 13;
+        const EMBED_LINKS = 1 << 14;
+        const ATTACH_FILES = 1 << 15;
+        const READ_MESSAGE_HISTORY = 1 << 16;
+        const MENTION_EVERYONE = 1 << 17;
+        const USE_EXTERNAL_EMOJIS = 1 << 18;
+        const VIEW_GUILD_INSIGHTS = 1 << 19;
+        const CONNECT = 1 << 20;
+        const SPEAK = 1 << 21;
+        const MUTE_MEMBERS = 1 << 22;
+        const DEAFEN_MEMBERS = 1 << 23;
+        const MOVE_MEMBERS = 1 << 24;
+        const USE_VAD = 1 << 25;
+        const CHANGE_NICKNAME = 1 << 26;
+        const MANAGE_NICKNAMES = 1 << 27;
+        const MANAGE_ROLES = 1 << 28;
+        const MANAGE_WEBHOOKS = 1 << 29;
+        const MANAGE_GUILD_EXPRESSIONS = 1 << 30;
+        const USE_APPLICATION_COMMANDS = 1 << 31;
+        const REQUEST_TO_SPEAK = 1 << 32;
+        const MANAGE_EVENTS = 1 << 33;
+        const MANAGE_THREADS = 1 << 34;
+        const CREATE_PUBLIC_THREADS = 1 << 35;
+        const CREATE_PRIVATE_THREADS = 1 << 36;
+        const USE_EXTERNAL_STICKERS = 1 << 37;
+        const SEND_MESSAGES_IN_THREADS = 1 << 38;
+        const USE_EMBEDDED_ACTIVITIES = 1 << 39;
+        const MODERATE_MEMBERS = 1 << 40;
+        const VIEW_CREATOR_MONETIZATION_ANALYTICS = 1 << 41;
+        const USE_SOUNDBOARD = 1 << 42;
+        const CREATE_GUILD_EXPRESSIONS = 1 << 43;
+        const CREATE_EVENTS = 1 << 44;
+        const USE_EXTERNAL_SOUNDS = 1 << 45;
+        const SEND_VOICE_MESSAGES = 1 << 46;
+        const SET_VOICE_CHANNEL_STATUS = 1 << 48;
+        const SEND_POLLS = 1 << 49;
+        const USE_EXTERNAL_APPS = 1 << 50;
+        const PIN_MESSAGES = 1 << 51;
+        const BYPASS_SLOWMODE = 1 << 52;
+    }
+}
+
+impl Permissions {
+    // What's left to a member that's timed out
+    pub const TIMED_OUT: Permissions = Permissions::VIEW_CHANNEL.union(Permissions::READ_MESSAGE_HISTORY);
+
+    // Lost along with SEND_MESSAGES
+    const NEEDS_SEND: Permissions = Permissions::SEND_TTS_MESSAGES
+        .union(Permissions::MENTION_EVERYONE)
+        .union(Permissions::EMBED_LINKS)
+        .union(Permissions::ATTACH_FILES);
+
+    /// What editing a member with `edit` (see `GuildMemberEditBuilder`) needs, `own` if it's us
+    pub fn for_member_edit(edit: &Value, own: bool) -> Self {
+        let mut required = Permissions::empty();
+        let has = |key: &str| edit.get(key).is_some();
+
+        if has("nick") {
+            required |= if own { Permissions::CHANGE_NICKNAME } else { Permissions::MANAGE_NICKNAMES };
+        }
+        if has("roles") {
+            required |= Permissions::MANAGE_ROLES;
+        }
+        if has("mute") {
+            required |= Permissions::MUTE_MEMBERS;
+        }
+        if has("deaf") {
+            required |= Permissions::DEAFEN_MEMBERS;
+        }
+        if has("channel_id") {
+            required |= Permissions::MOVE_MEMBERS;
+        }
+        if has("communication_disabled_until") {
+            required |= Permissions::MODERATE_MEMBERS;
+        }
+
+        required
+    }
+
+    pub fn apply_overwrite(self, overwrite: &PermissionOverwrite) -> Self {
+        (self - overwrite.deny) | overwrite.allow
+    }
+}
+
+impl Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        bitflags::parser::to_writer(self, f)
+    }
+}
+
+// Discord sends permissions as strings because they don't fit in a js number
+impl Serialize for Permissions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self.bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            String(String),
+            Number(u64),
+        }
+
+        let bits: u128 = match Repr::deserialize(deserializer)? {
+            Repr::String(s) => s
+                .parse()
+                .map_err(|e| de::Error::custom(format!("invalid permissions {s:?}: {e}")))?,
+            Repr::Number(n) => n.into(),
+        };
+
+        // Keep bits we don't know about yet, those past 64 don't fit and can't be checked anyway
+        Ok(Self::from_bits_retain(bits as u64))
+    }
+}
+
+/// Effective permissions of `member` in `guild`, or in `channel` if given
+///
+/// Follows discord's order: owner, @everyone and the member's roles (administrator means
+/// everything), then the channel's @everyone, role and member overwrites. Threads use
+/// their parent's overwrites, so None is returned when the parent isn't in `guild.channels`.
+pub fn compute_permissions(guild: &CachedGuild, member: &GuildMember, channel: Option<&Channel>) -> Option<Permissions> {
+    let user_id = member.user.as_ref().map(|u| u.id);
+    if user_id == Some(guild.properties.owner_id) {
+        return Some(Permissions::all());
+    }
+
+    let role = |id: Snowflake| guild.roles.iter().find(|r| r.id == id).map(|r| r.permissions);
+
+    // @everyone's role id is the guild id
+    let mut permissions = role(guild.id).unwrap_or_default();
+    for id in member.roles.iter() {
+        permissions |= role(*id).unwrap_or_default();
+    }
+
+    if permissions.contains(Permissions::ADMINISTRATOR) {
+        return Some(Permissions::all());
+    }
+
+    let timed_out = member
+        .communication_disabled_until
+        .as_ref()
+        .is_some_and(|t| t.0 > Utc::now());
+
+    let Some(channel) = channel else {
+        return Some(if timed_out { permissions & Permissions::TIMED_OUT } else { permissions });
+    };
+
+    let overwrites_from = match channel.is_thread() {
+        true => guild.channels.iter().find(|c| Some(c.id) == channel.parent_id)?,
+        false => channel,
+    };
+    let overwrites = overwrites_from.permission_overwrites.as_deref().unwrap_or_default();
+
+    if let Some(everyone) = overwrites.iter().find(|o| o.id == guild.id) {
+        permissions = permissions.apply_overwrite(everyone);
+    }
+
+    // Role overwrites are combined before applying, so allows win over denies between roles
+    let (allow, deny) = overwrites
+        .iter()
+        .filter(|o| o.overwrite_type == OverwriteType::Role && member.roles.contains(&o.id))
+        .fold((Permissions::empty(), Permissions::empty()), |(a, d), o| (a | o.allow, d | o.deny));
+    permissions = (permissions - deny) | allow;
+
+    if let Some(own) = overwrites
+        .iter()
+        .find(|o| o.overwrite_type == OverwriteType::Member && Some(o.id) == user_id)
+    {
+        permissions = permissions.apply_overwrite(own);
+    }
+
+    if timed_out {
+        permissions &= Permissions::TIMED_OUT;
+    }
+
+    // Without seeing the channel nothing else matters, without sending neither do its extras
+    if !permissions.contains(Permissions::VIEW_CHANNEL) {
+        return Some(Permissions::empty());
+    }
+    if !permissions.contains(Permissions::SEND_MESSAGES) {
+        permissions -= Permissions::NEEDS_SEND;
+    }
+
+    Some(permissions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const GUILD: u64 = 1;
+    const OWNER: u64 = 2;
+    const USER: u64 = 3;
+    const MODS: u64 = 10;
+    const HELPERS: u64 = 11;
+    const ADMINS: u64 = 12;
+
+    fn bits(p: Permissions) -> String {
+        p.bits().to_string()
+    }
+
+    fn role(id: u64, permissions: Permissions) -> Value {
+        json!({
+            "id": id.to_string(), "name": "role", "color": 0, "hoist": false, "position": 0,
+            "permissions": bits(permissions), "managed": false, "mentionable": false, "flags": 0,
+        })
+    }
+
+    fn guild() -> CachedGuild {
+        let everyone = Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES | Permissions::ATTACH_FILES;
+
+        serde_json::from_value(json!({
+            "id": GUILD.to_string(),
+            "joined_at": "2024-01-01T00:00:00+00:00",
+            "properties": {
+                "id": GUILD.to_string(), "name": "guild", "owner_id": OWNER.to_string(),
+                "preferred_locale": "en-US", "mfa_level": 0, "nsfw_level": 0, "verification_level": 0,
+                "default_message_notifications": 0, "explicit_content_filter": 0, "features": [],
+                "system_channel_flags": 0, "afk_timeout": 300, "premium_tier": 0,
+                "premium_progress_bar_enabled": false, "max_video_channel_users": 25,
+                "max_stage_video_channel_users": 50,
+            },
+            "large": false, "lazy": true, "member_count": 3, "premium_subscription_count": 0,
+            "channels": [], "threads": [], "stickers": [], "emojis": [],
+            "roles": [
+                role(GUILD, everyone),
+                role(MODS, Permissions::MANAGE_MESSAGES),
+                role(HELPERS, Permissions::empty()),
+                role(ADMINS, Permissions::ADMINISTRATOR),
+            ],
+            "data_mode": "full", "version": 0,
+        }))
+        .unwrap()
+    }
+
+    fn member(id: u64, roles: &[u64]) -> GuildMember {
+        serde_json::from_value(json!({
+            "user": { "id": id.to_string(), "username": "user" },
+            "roles": roles.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            "joined_at": "2024-01-01T00:00:00+00:00",
+            "deaf": false, "mute": false, "flags": 0,
+        }))
+        .unwrap()
+    }
+
+    // `overwrites` are (id, type, allow, deny) with type 0 for roles and 1 for members
+    fn channel(overwrites: &[(u64, u8, Permissions, Permissions)]) -> Channel {
+        let overwrites: Vec<Value> = overwrites
+            .iter()
+            .map(|(id, kind, allow, deny)| {
+                json!({ "id": id.to_string(), "type": kind, "allow": bits(*allow), "deny": bits(*deny) })
+            })
+            .collect();
+
+        serde_json::from_value(json!({
+            "id": "100", "type": 0, "flags": 0, "guild_id": GUILD.to_string(),
+            "permission_overwrites": overwrites,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn owner_has_everything() {
+        let guild = guild();
+        let deny_all = channel(&[(GUILD, 0, Permissions::empty(), Permissions::all())]);
+
+        assert_eq!(compute_permissions(&guild, &member(OWNER, &[]), Some(&deny_all)).unwrap(), Permissions::all());
+    }
+
+    #[test]
+    fn administrator_has_everything() {
+        let guild = guild();
+        let deny_all = channel(&[(GUILD, 0, Permissions::empty(), Permissions::all())]);
+
+        assert_eq!(compute_permissions(&guild, &member(USER, &[ADMINS]), Some(&deny_all)).unwrap(), Permissions::all());
+    }
+
+    #[test]
+    fn everyone_and_roles() {
+        let guild = guild();
+
+        let plain = compute_permissions(&guild, &member(USER, &[]), None).unwrap();
+        assert_eq!(plain, Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES | Permissions::ATTACH_FILES);
+
+        let moderator = compute_permissions(&guild, &member(USER, &[MODS]), None).unwrap();
+        assert_eq!(moderator, plain | Permissions::MANAGE_MESSAGES);
+    }
+
+    #[test]
+    fn role_overwrites() {
+        let guild = guild();
+        let channel = channel(&[
+            (GUILD, 0, Permissions::empty(), Permissions::SEND_MESSAGES),
+            (MODS, 0, Permissions::SEND_MESSAGES, Permissions::empty()),
+            (HELPERS, 0, Permissions::empty(), Permissions::SEND_MESSAGES),
+        ]);
+
+        // @everyone's deny applies, and losing SEND_MESSAGES takes ATTACH_FILES with it
+        let plain = compute_permissions(&guild, &member(USER, &[]), Some(&channel)).unwrap();
+        assert_eq!(plain, Permissions::VIEW_CHANNEL);
+
+        let moderator = compute_permissions(&guild, &member(USER, &[MODS]), Some(&channel)).unwrap();
+        assert!(moderator.contains(Permissions::SEND_MESSAGES | Permissions::ATTACH_FILES));
+
+        // Between roles an allow beats a deny
+        let both = compute_permissions(&guild, &member(USER, &[MODS, HELPERS]), Some(&channel)).unwrap();
+        assert_eq!(both, moderator);
+    }
+
+    #[test]
+    fn hidden_channel() {
+        let guild = guild();
+        let channel = channel(&[(GUILD, 0, Permissions::empty(), Permissions::VIEW_CHANNEL)]);
+
+        assert_eq!(compute_permissions(&guild, &member(USER, &[MODS]), Some(&channel)).unwrap(), Permissions::empty());
+    }
+
+    #[test]
+    fn member_overwrite_wins() {
+        let guild = guild();
+        let channel = channel(&[
+            (MODS, 0, Permissions::SEND_MESSAGES, Permissions::empty()),
+            (USER, 1, Permissions::EMBED_LINKS, Permissions::SEND_MESSAGES),
+        ]);
+
+        let permissions = compute_permissions(&guild, &member(USER, &[MODS]), Some(&channel)).unwrap();
+        assert!(!permissions.contains(Permissions::SEND_MESSAGES));
+        assert!(!permissions.contains(Permissions::EMBED_LINKS)); // needs SEND_MESSAGES
+        assert!(permissions.contains(Permissions::VIEW_CHANNEL | Permissions::MANAGE_MESSAGES));
+    }
+
+    fn thread(parent: u64) -> Channel {
+        serde_json::from_value(json!({
+            "id": "200", "type": 11, "flags": 0, "guild_id": GUILD.to_string(), "parent_id": parent.to_string(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn thread_uses_parent_overwrites() {
+        let mut guild = guild();
+        guild.channels.push(channel(&[(GUILD, 0, Permissions::empty(), Permissions::ATTACH_FILES)]));
+
+        let permissions = compute_permissions(&guild, &member(USER, &[]), Some(&thread(100))).unwrap();
+        assert_eq!(permissions, Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES);
+    }
+
+    #[test]
+    fn thread_without_cached_parent_is_unknown() {
+        let guild = guild();
+
+        assert_eq!(compute_permissions(&guild, &member(USER, &[]), Some(&thread(100))), None);
+        // Unless no overwrite could take anything away
+        assert_eq!(compute_permissions(&guild, &member(OWNER, &[]), Some(&thread(100))), Some(Permissions::all()));
+    }
+
+    #[test]
+    fn deserialize() {
+        let parse = |v: Value| serde_json::from_value::<Permissions>(v);
+
+        assert_eq!(parse(json!("8")).unwrap(), Permissions::ADMINISTRATOR);
+        assert_eq!(parse(json!(8)).unwrap(), Permissions::ADMINISTRATOR);
+        assert_eq!(parse(json!(u64::MAX.to_string())).unwrap().bits(), u64::MAX);
+
+        // Bits past 64 are dropped instead of failing the whole payload
+        let wide = (1u128 << 70) | Permissions::ADMINISTRATOR.bits() as u128;
+        assert_eq!(parse(json!(wide.to_string())).unwrap(), Permissions::ADMINISTRATOR);
+        assert!(parse(json!("not a number")).is_err());
+        assert!(parse(json!("-1")).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use crate::types::common::Emoji;
+use crate::types::enums::wire_enum;
 use crate::types::timestamp::Timestamp;
 use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Media {
@@ -22,10 +22,11 @@ pub struct AnswerCount {
     pub me_voted: bool,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum LayoutType {
-    Default = 1,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum LayoutType {
+        Default = 1,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -1,28 +1,30 @@
 use std::fmt::Display;
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use super::activity::Activity;
+use crate::error::Error;
 
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum StatusType {
-    Online,
-    Dnd,
-    Idle,
-    Invisible,
-    Offline,
+use super::{
+    activity::{Activity, ActivityType},
+    enums::wire_str_enum,
+    user::PartialUser,
+    Snowflake,
+};
+
+wire_str_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum StatusType {
+        Online = "online",
+        Dnd = "dnd",
+        Idle = "idle",
+        Invisible = "invisible",
+        Offline = "offline",
+    }
 }
 
 impl Display for StatusType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let str = match self {
-            StatusType::Online => "online".to_string(),
-            StatusType::Dnd => "dnd".to_string(),
-            StatusType::Idle => "idle".to_string(),
-            StatusType::Invisible => "invisible".to_string(),
-            StatusType::Offline => "offline".to_string(),
-        };
-        write!(f, "{}", str)
+        f.write_str(self.as_str())
     }
 }
 
@@ -33,3 +35,66 @@ pub struct GatewayPresence {
     pub status: StatusType,
     pub afk: bool,
 }
+
+impl GatewayPresence {
+    pub fn new(status: StatusType, activities: Vec<Activity>) -> Self {
+        Self {
+            since: None,
+            activities,
+            status,
+            afk: false,
+        }
+    }
+
+    // Catches what discord would otherwise silently ignore or close the connection over
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |s: &str| Err(Error::InvalidApiRequest(s.to_string()).into());
+
+        if self.status == StatusType::Offline {
+            return invalid("status can't be offline, use invisible instead");
+        }
+
+        for activity in self.activities.iter() {
+            if activity.activity_type == ActivityType::Custom {
+                if activity.state.is_none() && activity.emoji.is_none() {
+                    return invalid("custom status needs a state or an emoji");
+                }
+            } else if activity.name.is_empty() {
+                return invalid("activity name can't be empty");
+            }
+
+            if activity.url.as_ref().is_some_and(|url| url.len() > 512) {
+                return invalid("activity url must be less than 512 characters");
+            }
+
+            if let Some(buttons) = activity.buttons.as_ref() {
+                if buttons.len() > 2 {
+                    return invalid("can't have more than 2 buttons in one activity");
+                }
+
+                if buttons.iter().any(|b| b.label.len() > 32 || b.url.len() > 512) {
+                    return invalid("button label must be less than 32 characters and url less than 512");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ClientStatus {
+    pub desktop: Option<StatusType>,
+    pub mobile: Option<StatusType>,
+    pub web: Option<StatusType>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Presence {
+    pub user: PartialUser, // the rest of the user is only sent when it changed
+    pub guild_id: Option<Snowflake>,
+    pub status: StatusType,
+    #[serde(default)]
+    pub activities: Vec<Activity>,
+    pub client_status: Option<ClientStatus>,
+}
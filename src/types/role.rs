@@ -1,7 +1,9 @@
+use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use super::Snowflake;
+use super::{permissions::Permissions, Snowflake};
+use super::flags::wire_flags;
 
 /**
  * This is stupid
@@ -28,11 +30,13 @@ pub struct RoleSubscriptionData {
     pub is_renewal: bool,
 }
 
-#[derive(Debug)]
-#[repr(u8)]
-pub enum RoleFlags {
-    InPrompt = 1 << 0,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct RoleFlags: u64 {
+        const IN_PROMPT = 1 << 0;
+    }
 }
+wire_flags!(RoleFlags);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Role {
@@ -42,18 +46,24 @@ pub struct Role {
     pub hoist: bool,
 
     pub position: u64,
-    pub permissions: String, // String because bigint
+    pub permissions: Permissions,
 
     pub managed: bool,
     pub mentionable: bool,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<RoleTag>,
 
-    pub flags: u8,
+    pub flags: RoleFlags,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>, // icon hash
-    #[serde(rename = "unicode_emoji")]
+    #[serde(rename = "unicode_emoji", skip_serializing_if = "Option::is_none")]
     pub emoji: Option<String>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -62,7 +72,7 @@ pub struct RoleBuilder {
 }
 
 impl RoleBuilder {
-    pub fn new(name: String, permissions: String) -> Self {
+    pub fn new(name: String, permissions: Permissions) -> Self {
         Self {
             value: json!({
                 "name": name,
@@ -0,0 +1,338 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::Method;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::MutexGuard;
+
+use crate::{context::Context, error::Error};
+
+use super::discord_proto::{FrecencyUserSettings, PreloadedUserSettings};
+use super::enums::wire_enum;
+
+// How many times an update is re-applied when someone else changed the settings in between
+const MAX_UPDATE_ATTEMPTS: u32 = 3;
+
+wire_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SettingsType {
+        Preloaded = 1,
+        Frecency = 2,
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SettingsProto {
+    #[serde(rename = "type")]
+    pub kind: SettingsType,
+    pub proto: String, // base64
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserSettingsProtoUpdate {
+    pub settings: SettingsProto,
+    pub partial: bool, // merge into what we have instead of replacing it
+}
+
+pub fn decode<M: Message + Default>(proto: &str) -> Result<M> {
+    Ok(M::decode(STANDARD.decode(proto)?.as_slice())?)
+}
+
+pub fn encode<M: Message>(settings: &M) -> String {
+    STANDARD.encode(settings.encode_to_vec())
+}
+
+// A partial proto replaces the top-level fields it carries. Merging it in would append to
+// repeated fields instead of replacing them.
+fn apply<M: UserSettingsProto>(current: &mut Option<M>, proto: &str, partial: bool) -> Result<()> {
+    let update: M = decode(proto)?;
+
+    match current {
+        Some(settings) if partial => settings.replace_present(update),
+        _ => *current = Some(update),
+    }
+
+    Ok(())
+}
+
+/// Last known settings protos of the logged in account
+#[derive(Default, Debug, Clone)]
+pub struct UserSettingsState {
+    pub preloaded: Option<PreloadedUserSettings>,
+    pub frecency: Option<FrecencyUserSettings>,
+}
+
+impl UserSettingsState {
+    pub fn apply(&mut self, update: &UserSettingsProtoUpdate) -> Result<()> {
+        match update.settings.kind {
+            SettingsType::Preloaded => apply(&mut self.preloaded, &update.settings.proto, update.partial),
+            SettingsType::Frecency => apply(&mut self.frecency, &update.settings.proto, update.partial),
+            SettingsType::Unknown(_) => Ok(()), // not a proto we decode
+        }
+    }
+}
+
+/// A settings proto that lives at `/users/@me/settings-proto/{type}`
+pub trait UserSettingsProto: Message + Default + Clone + PartialEq {
+    const KIND: SettingsType;
+
+    fn data_version(&self) -> u32;
+
+    /// Top-level fields of `new` that differ from `old`
+    ///
+    /// Discord replaces each top-level field it's sent, so every changed one is included whole:
+    /// anything cleared inside it (e.g. `status.custom_status`) is left out of the replacement.
+    /// Cleared top-level fields are sent as empty messages.
+    fn diff(old: &Self, new: &Self) -> Self;
+
+    /// Replaces every top-level field that is set in `partial`
+    fn replace_present(&mut self, partial: Self);
+
+    fn cached(state: &mut UserSettingsState) -> &mut Option<Self>;
+}
+
+macro_rules! settings_proto {
+    ($ty:ty, $kind:expr, $state:ident, [$($field:ident),* $(,)?]) => {
+        impl UserSettingsProto for $ty {
+            const KIND: SettingsType = $kind;
+
+            fn data_version(&self) -> u32 {
+                self.versions.as_ref().map(|v| v.data_version).unwrap_or_default()
+            }
+
+            fn diff(old: &Self, new: &Self) -> Self {
+                let mut diff = Self::default();
+                $(
+                    if old.$field != new.$field {
+                        diff.$field = Some(new.$field.clone().unwrap_or_default());
+                    }
+                )*
+                diff
+            }
+
+            fn replace_present(&mut self, partial: Self) {
+                if partial.versions.is_some() {
+                    self.versions = partial.versions;
+                }
+                $(
+                    if partial.$field.is_some() {
+                        self.$field = partial.$field;
+                    }
+                )*
+            }
+
+            fn cached(state: &mut UserSettingsState) -> &mut Option<Self> {
+                &mut state.$state
+            }
+        }
+    };
+}
+
+settings_proto!(PreloadedUserSettings, SettingsType::Preloaded, preloaded, [
+    inbox, guilds, user_content, voice_and_video, text_and_images, notifications, privacy, debug,
+    game_library, status, localization, appearance, guild_folders, favorites, audio_context_settings,
+    communities, broadcast, clips,
+]);
+
+settings_proto!(FrecencyUserSettings, SettingsType::Frecency, frecency, [
+    favorite_gifs, favorite_stickers, sticker_frecency, favorite_emojis, emoji_frecency,
+    application_command_frecency, favorite_soundboard_sounds, application_frecency,
+    heard_sound_frecency, played_sound_frecency, guild_and_channel_frecency,
+]);
+
+// What to PATCH to get from `current` to `new`, None if nothing changed
+fn patch_body<M: UserSettingsProto>(current: &M, new: &M) -> Option<serde_json::Value> {
+    let diff = M::diff(current, new);
+    if diff == M::default() {
+        return None;
+    }
+
+    Some(json!({
+        "settings": encode(&diff),
+        "required_data_version": current.data_version(),
+    }))
+}
+
+pub struct UserSettings;
+
+impl UserSettings {
+    /// Fetches the current settings and stores them in `ctx.settings`
+    pub async fn fetch<M: UserSettingsProto>(ctx: &mut MutexGuard<'_, Context>) -> Result<M> {
+        let resp = ctx
+            .request(Method::GET, &format!("/v9/users/@me/settings-proto/{}", M::KIND.value()), None)
+            .await?
+            .error_for_status()?;
+
+        let settings: M = decode(resp.body["settings"].as_str().unwrap_or_default())?;
+        *M::cached(&mut ctx.settings) = Some(settings.clone());
+
+        Ok(settings)
+    }
+
+    /// Applies `f` to the current settings and sends only what changed
+    ///
+    /// If discord says our copy was out of date, `f` is applied again to the fresh settings.
+    /// Resolves to the settings discord ended up with.
+    pub async fn update<M: UserSettingsProto>(
+        ctx: &mut MutexGuard<'_, Context>,
+        mut f: impl FnMut(&mut M),
+    ) -> Result<M> {
+        let mut current = match M::cached(&mut ctx.settings).clone() {
+            Some(settings) => settings,
+            None => Self::fetch(ctx).await?,
+        };
+
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let mut new = current.clone();
+            f(&mut new);
+
+            let Some(body) = patch_body(&current, &new) else {
+                return Ok(current);
+            };
+
+            let resp = ctx
+                .request(
+                    Method::PATCH,
+                    &format!("/v9/users/@me/settings-proto/{}", M::KIND.value()),
+                    Some(body),
+                )
+                .await?
+                .error_for_status()?;
+
+            let settings: M = decode(resp.body["settings"].as_str().unwrap_or_default())?;
+            *M::cached(&mut ctx.settings) = Some(settings.clone());
+
+            if !resp.body["out_of_date"].as_bool().unwrap_or(false) {
+                return Ok(settings);
+            }
+
+            log::debug!("Settings were out of date, retrying update");
+            current = settings;
+        }
+
+        Err(Error::SettingsOutOfDate(MAX_UPDATE_ATTEMPTS).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::discord_proto::preloaded_user_settings::{CustomStatus, GuildFolders, StatusSettings, Versions};
+
+    fn update(settings: &PreloadedUserSettings, partial: bool) -> UserSettingsProtoUpdate {
+        UserSettingsProtoUpdate {
+            settings: SettingsProto {
+                kind: SettingsType::Preloaded,
+                proto: encode(settings),
+            },
+            partial,
+        }
+    }
+
+    fn folders(positions: &[u64]) -> Option<GuildFolders> {
+        Some(GuildFolders {
+            folders: vec![],
+            guild_positions: positions.to_vec(),
+        })
+    }
+
+    #[test]
+    fn partial_update_replaces_present_fields() {
+        let mut state = UserSettingsState::default();
+        let full = PreloadedUserSettings {
+            guild_folders: folders(&[1, 2]),
+            status: Some(StatusSettings {
+                status: Some("dnd".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        state.apply(&update(&full, false)).unwrap();
+
+        let partial = PreloadedUserSettings {
+            guild_folders: folders(&[3]),
+            ..Default::default()
+        };
+        state.apply(&update(&partial, true)).unwrap();
+
+        let settings = state.preloaded.unwrap();
+        assert_eq!(settings.guild_folders, folders(&[3]));
+        assert_eq!(settings.status.unwrap().status.as_deref(), Some("dnd"));
+    }
+
+    #[test]
+    fn full_update_replaces_everything() {
+        let mut state = UserSettingsState::default();
+        let first = PreloadedUserSettings {
+            guild_folders: folders(&[1, 2]),
+            ..Default::default()
+        };
+        state.apply(&update(&first, false)).unwrap();
+        state.apply(&update(&PreloadedUserSettings::default(), false)).unwrap();
+
+        assert_eq!(state.preloaded, Some(PreloadedUserSettings::default()));
+    }
+
+    fn with_custom_status() -> PreloadedUserSettings {
+        PreloadedUserSettings {
+            versions: Some(Versions {
+                data_version: 7,
+                ..Default::default()
+            }),
+            guild_folders: folders(&[1, 2]),
+            status: Some(StatusSettings {
+                status: Some("dnd".to_string()),
+                custom_status: Some(CustomStatus {
+                    text: "busy".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn sent(body: &serde_json::Value) -> PreloadedUserSettings {
+        decode(body["settings"].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn clearing_sends_whole_parent() {
+        let current = with_custom_status();
+        let mut new = current.clone();
+        new.status.as_mut().unwrap().custom_status = None;
+
+        let body = patch_body(&current, &new).unwrap();
+        assert_eq!(body["required_data_version"], 7);
+
+        // Only the status settings are sent, whole and without the custom status
+        let expected = PreloadedUserSettings {
+            status: new.status.clone(),
+            ..Default::default()
+        };
+        assert_eq!(sent(&body), expected);
+    }
+
+    #[test]
+    fn clearing_top_level_sends_empty_message() {
+        let current = with_custom_status();
+        let new = PreloadedUserSettings {
+            guild_folders: None,
+            ..current.clone()
+        };
+
+        let body = patch_body(&current, &new).unwrap();
+        let expected = PreloadedUserSettings {
+            guild_folders: Some(GuildFolders::default()),
+            ..Default::default()
+        };
+        assert_eq!(sent(&body), expected);
+    }
+
+    #[test]
+    fn no_changes_no_request() {
+        let current = with_custom_status();
+        assert!(patch_body(&current, &current.clone()).is_none());
+    }
+}
@@ -14,9 +14,27 @@ impl Display for Snowflake {
         write!(f, "{}", self.0)
     }
 }
-impl From<String> for Snowflake {
-    fn from(value: String) -> Self {
-        Self(value.parse().expect("Couldn't parse snowflake from string"))
+
+// Replaces the old `From<String>`, which panicked on anything that wasn't a number. Both can't
+// exist at once (the std blanket impl would conflict), so callers have to switch to `try_into`
+impl TryFrom<String> for Snowflake {
+    type Error = ParseIntError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+// Protos store snowflakes as plain integers
+impl From<u64> for Snowflake {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Snowflake> for u64 {
+    fn from(value: Snowflake) -> Self {
+        value.0
     }
 }
 
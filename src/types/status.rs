@@ -0,0 +1,232 @@
+use anyhow::Result;
+use chrono::Utc;
+use tokio::sync::MutexGuard;
+
+use crate::{context::Context, websocket::Websocket};
+
+use super::{
+    activity::{Activity, ActivityFlag, ActivityType},
+    common::Emoji,
+    discord_proto::{
+        preloaded_user_settings::{CustomStatus, StatusSettings},
+        PreloadedUserSettings,
+    },
+    presence::{GatewayPresence, StatusType},
+    settings::UserSettings,
+    timestamp::Timestamp,
+};
+
+/// Status and custom status of the logged in account
+///
+/// Changes are saved in the settings proto, which is what other clients show,
+/// and sent as a gateway presence so this session agrees with it. Before the websocket is
+/// logged in, the presence is only stored and sent with identify.
+pub struct Status;
+
+impl Status {
+    pub async fn set_custom_status(
+        ctx: &mut MutexGuard<'_, Context>,
+        ws: &mut Websocket,
+        text: &str,
+        emoji: Option<Emoji>,
+        expires_at: Option<Timestamp>,
+    ) -> Result<()> {
+        let custom_status = Self::custom_status(text, emoji, expires_at);
+        Self::update(ctx, ws, |s| s.custom_status = Some(custom_status.clone())).await
+    }
+
+    pub async fn clear_custom_status(ctx: &mut MutexGuard<'_, Context>, ws: &mut Websocket) -> Result<()> {
+        Self::update(ctx, ws, |s| s.custom_status = None).await
+    }
+
+    // `show_current_activity` decides whether activities other than the custom status are shown
+    pub async fn set_status(
+        ctx: &mut MutexGuard<'_, Context>,
+        ws: &mut Websocket,
+        status: StatusType,
+        show_current_activity: bool,
+    ) -> Result<()> {
+        Self::update(ctx, ws, |s| {
+            s.status = Some(status.to_string());
+            s.show_current_game = Some(show_current_activity);
+        })
+        .await
+    }
+
+    async fn update(
+        ctx: &mut MutexGuard<'_, Context>,
+        ws: &mut Websocket,
+        mut f: impl FnMut(&mut StatusSettings),
+    ) -> Result<()> {
+        let settings = UserSettings::update(ctx, |s: &mut PreloadedUserSettings| {
+            f(s.status.get_or_insert_with(Default::default))
+        })
+        .await?;
+
+        let current = ctx.presence.as_ref().map(|p| p.activities.as_slice()).unwrap_or_default();
+        let presence = Self::presence(&settings.status.unwrap_or_default(), current);
+        presence.validate()?;
+
+        if ws.ready {
+            return ws.update_presence(ctx, presence).await;
+        }
+
+        ctx.presence = Some(presence);
+        Ok(())
+    }
+
+    fn custom_status(text: &str, emoji: Option<Emoji>, expires_at: Option<Timestamp>) -> CustomStatus {
+        CustomStatus {
+            text: text.to_string(),
+            emoji_id: emoji.as_ref().and_then(|e| e.id).map(u64::from).unwrap_or_default(),
+            emoji_name: emoji.and_then(|e| e.name).unwrap_or_default(),
+            expires_at_ms: expires_at.map(|t| t.0.timestamp_millis() as u64).unwrap_or_default(),
+        }
+    }
+
+    /// Gateway presence matching `settings`, keeping the non-custom activities in `activities`
+    pub fn presence(settings: &StatusSettings, activities: &[Activity]) -> GatewayPresence {
+        let status = match settings.status.as_deref() {
+            Some("idle") => StatusType::Idle,
+            Some("dnd") => StatusType::Dnd,
+            Some("invisible") => StatusType::Invisible,
+            _ => StatusType::Online,
+        };
+
+        let mut activities: Vec<Activity> = match settings.show_current_game {
+            Some(false) => vec![],
+            _ => activities
+                .iter()
+                .filter(|a| a.activity_type != ActivityType::Custom)
+                .cloned()
+                .collect(),
+        };
+
+        if let Some(custom) = settings.custom_status.as_ref().and_then(Self::custom_activity) {
+            activities.insert(0, custom);
+        }
+
+        GatewayPresence::new(status, activities)
+    }
+
+    // None when there's nothing to show or it already expired
+    fn custom_activity(custom: &CustomStatus) -> Option<Activity> {
+        let expired = custom.expires_at_ms != 0 && custom.expires_at_ms <= Utc::now().timestamp_millis() as u64;
+        if expired || (custom.text.is_empty() && custom.emoji_name.is_empty()) {
+            return None;
+        }
+
+        let emoji = (!custom.emoji_name.is_empty()).then(|| Emoji {
+            id: (custom.emoji_id != 0).then(|| custom.emoji_id.into()),
+            name: Some(custom.emoji_name.clone()),
+            roles: None,
+            creator: None,
+            require_colons: None,
+            managed: None,
+            animated: None,
+            available: None,
+            #[cfg(feature = "extra-fields")]
+            extra: Default::default(),
+        });
+
+        Some(Activity {
+            name: "Custom Status".to_string(),
+            activity_type: ActivityType::Custom,
+            url: None,
+            created_at: 0,
+            timestamps: None,
+            application_id: None,
+            details: None,
+            state: (!custom.text.is_empty()).then(|| custom.text.clone()),
+            emoji,
+            party: None,
+            assets: None,
+            secrets: None,
+            instance: None,
+            buttons: None,
+            flags: ActivityFlag::empty(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::json;
+
+    fn game(name: &str) -> Activity {
+        serde_json::from_value(json!({ "name": name, "type": 0 })).unwrap()
+    }
+
+    fn emoji(id: Option<u64>, name: &str) -> Emoji {
+        serde_json::from_value(json!({ "id": id.map(|id| id.to_string()), "name": name })).unwrap()
+    }
+
+    fn settings(status: &str, custom: Option<CustomStatus>, show_current_game: Option<bool>) -> StatusSettings {
+        StatusSettings {
+            status: Some(status.to_string()),
+            custom_status: custom,
+            show_current_game,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn custom_status_from_arguments() {
+        let expires_at = Timestamp((Utc::now() + Duration::hours(1)).fixed_offset());
+        let custom = Status::custom_status("hi", Some(emoji(Some(42), "wave")), Some(expires_at.clone()));
+
+        assert_eq!(custom.text, "hi");
+        assert_eq!(custom.emoji_id, 42);
+        assert_eq!(custom.emoji_name, "wave");
+        assert_eq!(custom.expires_at_ms, expires_at.0.timestamp_millis() as u64);
+
+        // Unset values are the proto defaults, which Discord reads as "none"
+        let custom = Status::custom_status("", Some(emoji(None, "🙂")), None);
+        assert_eq!((custom.emoji_id, custom.expires_at_ms), (0, 0));
+    }
+
+    #[test]
+    fn custom_status_goes_first_and_replaces_old_one() {
+        let custom = Status::custom_status("hi", Some(emoji(None, "🙂")), None);
+        let old_custom = Status::custom_activity(&Status::custom_status("old", None, None)).unwrap();
+
+        let presence = Status::presence(&settings("dnd", Some(custom), None), &[old_custom, game("chess")]);
+
+        assert_eq!(presence.status, StatusType::Dnd);
+        let names: Vec<&str> = presence.activities.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["Custom Status", "chess"]);
+        assert_eq!(presence.activities[0].state.as_deref(), Some("hi"));
+        assert_eq!(presence.activities[0].emoji.as_ref().unwrap().name.as_deref(), Some("🙂"));
+    }
+
+    #[test]
+    fn hidden_activity_keeps_only_custom_status() {
+        let custom = Status::custom_status("hi", None, None);
+        let presence = Status::presence(&settings("idle", Some(custom), Some(false)), &[game("chess")]);
+
+        assert_eq!(presence.status, StatusType::Idle);
+        assert_eq!(presence.activities.len(), 1);
+        assert_eq!(presence.activities[0].activity_type, ActivityType::Custom);
+    }
+
+    #[test]
+    fn empty_or_expired_custom_status_is_not_shown() {
+        let expired = Status::custom_status("hi", None, Some(Timestamp((Utc::now() - Duration::minutes(1)).fixed_offset())));
+        let empty = Status::custom_status("", None, None);
+
+        for custom in [expired, empty] {
+            let presence = Status::presence(&settings("online", Some(custom), None), &[game("chess")]);
+            let names: Vec<&str> = presence.activities.iter().map(|a| a.name.as_str()).collect();
+            assert_eq!(names, ["chess"]);
+        }
+    }
+
+    #[test]
+    fn unknown_status_falls_back_to_online() {
+        let presence = Status::presence(&settings("streaming", None, None), &[]);
+        assert_eq!(presence.status, StatusType::Online);
+        assert!(presence.activities.is_empty());
+    }
+}
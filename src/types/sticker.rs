@@ -1,22 +1,24 @@
 use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use super::{user::User, Snowflake};
+use super::enums::wire_enum;
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum StickerType {
-    Standard = 1, // "Official" sticker
-    Guild = 2,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum StickerType {
+        Standard = 1, // "Official" sticker
+        Guild = 2,
+    }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum StickerFormat {
-    PNG = 1,
-    APNG = 2,
-    LOTTIE = 3,
-    GIF = 4,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum StickerFormat {
+        PNG = 1,
+        APNG = 2,
+        LOTTIE = 3,
+        GIF = 4,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -29,6 +31,7 @@ pub struct StickerItem {
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Sticker {
     pub id: Snowflake,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub pack_id: Option<Snowflake>,
     pub guild_id: Snowflake,
 
@@ -41,11 +44,17 @@ pub struct Sticker {
     pub sticker_type: StickerType,
     pub format_type: StickerFormat,
 
-    #[serde(rename = "user")]
+    #[serde(rename = "user", skip_serializing_if = "Option::is_none")]
     pub creator: Option<User>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub available: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sort_value: Option<u64>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -1,7 +1,8 @@
 use crate::types::Snowflake;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, ParseError, SecondsFormat};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::Display;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Timestamp(pub DateTime<FixedOffset>);
@@ -30,9 +31,20 @@ impl From<Timestamp> for String {
     }
 }
 
-impl From<String> for Timestamp {
-    fn from(value: String) -> Self {
-        Self(value.parse().expect("Couldn't parse timestamp from string"))
+impl FromStr for Timestamp {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+// Replaces the old panicking `From<String>`, see `Snowflake`'s `TryFrom<String>`
+impl TryFrom<String> for Timestamp {
+    type Error = ParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
     }
 }
 
@@ -43,7 +55,6 @@ impl<'de> Deserialize<'de> for Timestamp {
     {
         String::deserialize(deserializer)?
             .parse()
-            .map(Timestamp)
             .map_err(serde::de::Error::custom)
     }
 }
@@ -53,7 +64,13 @@ impl Serialize for Timestamp {
     where
         S: Serializer,
     {
-        serializer.collect_str(&self.0.to_rfc3339())
+        // Discord's own formats, so payloads re-serialize the way they came in: microseconds
+        // for most timestamps, none at all for e.g. `last_pin_timestamp`
+        let format = match self.0.timestamp_subsec_nanos() {
+            0 => SecondsFormat::Secs,
+            _ => SecondsFormat::Micros,
+        };
+        serializer.collect_str(&self.0.to_rfc3339_opts(format, false))
     }
 }
 
@@ -61,8 +78,9 @@ impl From<Snowflake> for Timestamp {
     fn from(value: Snowflake) -> Self {
         let ms = value.timestamp();
         Self(
+            // 42 bits of milliseconds after 2015 are always in range
             DateTime::from_timestamp(ms as i64 / 1000, (ms % 1000) as u32 * 1_000_000)
-                .unwrap()
+                .unwrap_or_default()
                 .into(),
         )
     }
@@ -1,7 +1,10 @@
+use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use super::common::{merge_fields, nullable};
 use super::Snowflake;
+use super::enums::wire_enum;
+use super::flags::wire_flags;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AvatarDecorationData {
@@ -13,18 +16,22 @@ pub struct AvatarDecorationData {
 pub struct UserClan {
     #[serde(rename = "identity_enabled")]
     pub shown: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub badge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub identity_guild_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tag: Option<String>,
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum RelationshipType {
-    Friend = 1,
-    Bot = 2,
-    IncomingFriendRequest = 3,
-    OutgoingFriendRequest = 4,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum RelationshipType {
+        Friend = 1,
+        Bot = 2,
+        IncomingFriendRequest = 3,
+        OutgoingFriendRequest = 4,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -36,33 +43,42 @@ pub struct Relationship {
     pub user_type: RelationshipType,
 }
 
-#[derive(Deserialize_repr, Serialize_repr, Debug, Eq, PartialEq, Clone)]
-#[repr(u8)]
-pub enum PremiumType {
-    None = 0,
-    NitroClassic = 1,
-    Nitro = 2,
-    NitroBasic = 3,
+wire_enum! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum PremiumType {
+        None = 0,
+        NitroClassic = 1,
+        Nitro = 2,
+        NitroBasic = 3,
+    }
 }
 
-#[repr(u32)]
-pub enum UserFlags {
-    Staff = 1 << 0,
-    Partner = 1 << 1,
-    Hypesquad = 1 << 2,
-    BugHunterLvl1 = 1 << 3,
-    HypesquadBravery = 1 << 6,
-    HypesquadBrilliance = 1 << 7,
-    HypesquadBalance = 1 << 8,
-    EarlyNitroSupporter = 1 << 9,
-    TeamPseudoUser = 1 << 10,
-    BugHunterLvl2 = 1 << 14,
-    VerifiedBot = 1 << 16,
-    VerifiedDev = 1 << 17,
-    CertifiedMod = 1 << 18,
-    BotHTTPInteractions = 1 << 19, // bot uses only http interactions
-    ActiveDev = 1 << 22,
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct UserFlags: u64 {
+        const STAFF = 1 << 0;
+        const PARTNER = 1 << 1;
+        const HYPESQUAD = 1 << 2;
+        const BUG_HUNTER_LVL1 = 1 << 3;
+        const MFA_SMS = 1 << 4;
+        const PREMIUM_PROMO_DISMISSED = 1 << 5;
+        const HYPESQUAD_BRAVERY = 1 << 6;
+        const HYPESQUAD_BRILLIANCE = 1 << 7;
+        const HYPESQUAD_BALANCE = 1 << 8;
+        const EARLY_NITRO_SUPPORTER = 1 << 9;
+        const TEAM_PSEUDO_USER = 1 << 10;
+        const HAS_UNREAD_URGENT_MESSAGES = 1 << 13;
+        const BUG_HUNTER_LVL2 = 1 << 14;
+        const VERIFIED_BOT = 1 << 16;
+        const VERIFIED_DEV = 1 << 17;
+        const CERTIFIED_MOD = 1 << 18;
+        const BOT_HTTP_INTERACTIONS = 1 << 19;
+        const SPAMMER = 1 << 20;
+        const ACTIVE_DEV = 1 << 22;
+        const QUARANTINED = 1 << 44;
+    }
 }
+wire_flags!(UserFlags);
 
 fn default_bot_value() -> bool {
     false
@@ -72,31 +88,125 @@ pub struct User {
     pub id: Snowflake,
     pub username: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub global_name: Option<String>, // bots: application name
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar: Option<String>, // avatar hash
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar_decoration_data: Option<AvatarDecorationData>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub banner: Option<String>,    // banner hash
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub accent_color: Option<u32>, // hex color
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub locale: Option<String>,
 
-    #[serde(rename = "bot", default = "default_bot_value")]
+    #[serde(rename = "bot", default = "default_bot_value", skip_serializing_if = "std::ops::Not::not")]
     pub is_bot: bool,
-    #[serde(rename = "system", default = "default_bot_value")]
+    #[serde(rename = "system", default = "default_bot_value", skip_serializing_if = "std::ops::Not::not")]
     pub is_system: bool,
 
     // These two are oath2 "email" scope only
-    #[serde(rename = "verified")]
+    #[serde(rename = "verified", skip_serializing_if = "Option::is_none")]
     pub is_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub email: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mfa_enabled: Option<bool>,
 
-    pub public_flags: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_flags: Option<UserFlags>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub premium_type: Option<PremiumType>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
+}
+
+/// A user where only `id` is guaranteed, e.g. in PRESENCE_UPDATE
+///
+/// Missing fields are `None`, fields that were set to null are `Some(None)`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PartialUser {
+    pub id: Snowflake,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub global_name: Option<Option<String>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub avatar_decoration_data: Option<Option<AvatarDecorationData>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub banner: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub accent_color: Option<Option<u32>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub locale: Option<Option<String>>,
+
+    #[serde(rename = "bot", skip_serializing_if = "Option::is_none")]
+    pub is_bot: Option<bool>,
+    #[serde(rename = "system", skip_serializing_if = "Option::is_none")]
+    pub is_system: Option<bool>,
+
+    #[serde(rename = "verified", default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub is_verified: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub email: Option<Option<String>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub mfa_enabled: Option<Option<bool>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub public_flags: Option<Option<UserFlags>>,
+
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub premium_type: Option<Option<PremiumType>>,
+
+    #[cfg(feature = "extra-fields")]
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>, // fields we don't model
+}
+
+impl PartialUser {
+    pub fn new(id: Snowflake) -> Self {
+        Self {
+            id,
+            username: None,
+            global_name: None,
+            avatar: None,
+            avatar_decoration_data: None,
+            banner: None,
+            accent_color: None,
+            locale: None,
+            is_bot: None,
+            is_system: None,
+            is_verified: None,
+            email: None,
+            mfa_enabled: None,
+            public_flags: None,
+            premium_type: None,
+            #[cfg(feature = "extra-fields")]
+            extra: Default::default(),
+        }
+    }
+
+    pub fn apply_to(&self, user: &mut User) {
+        merge_fields!(self => user;
+            username, global_name, avatar, avatar_decoration_data, banner, accent_color, locale,
+            is_bot, is_system, is_verified, email, mfa_enabled, public_flags, premium_type,
+        );
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -134,6 +244,32 @@ pub struct BotUser {
     pub premium_type: u32,
 
     pub purchased_flags: u32,
-    pub public_flags: Option<u64>,
-    pub flags: u64,
+    pub public_flags: Option<UserFlags>,
+    pub flags: UserFlags,
+}
+
+// Ourselves as other users see us, e.g. for our own guild members
+impl From<&BotUser> for User {
+    fn from(user: &BotUser) -> Self {
+        Self {
+            id: user.id,
+            username: user.username.clone(),
+            global_name: user.global_name.clone(),
+            avatar: user.avatar.clone(),
+            avatar_decoration_data: user.avatar_decoration_data.clone(),
+            banner: user.banner.clone(),
+            accent_color: user.accent_color.map(|c| c as u32),
+            locale: None,
+            is_bot: false,
+            is_system: false,
+            is_verified: Some(user.is_verified),
+            email: user.email.clone(),
+            mfa_enabled: Some(user.mfa_enabled),
+            public_flags: user.public_flags,
+            premium_type: Some(PremiumType::from(user.premium_type as u64)),
+
+            #[cfg(feature = "extra-fields")]
+            extra: Default::default(),
+        }
+    }
 }
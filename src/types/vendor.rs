@@ -1,5 +1,9 @@
+// prost nests each message's types in a module named after the file
+#[allow(clippy::module_inception)]
 mod frecency_user_settings;
+#[allow(clippy::module_inception)]
 mod guild_settings;
+#[allow(clippy::module_inception)]
 mod preloaded_user_settings;
 
 pub mod discord_proto {
@@ -0,0 +1,101 @@
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+use super::{guild::GuildMember, timestamp::Timestamp, Snowflake};
+use super::flags::wire_flags;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VoiceState {
+    pub guild_id: Option<Snowflake>,
+    pub channel_id: Option<Snowflake>, // None when leaving
+    pub user_id: Snowflake,
+    pub member: Option<GuildMember>,
+    pub session_id: String,
+
+    // Server side
+    pub deaf: bool,
+    pub mute: bool,
+    pub suppress: bool, // stage channels
+
+    // Client side
+    pub self_deaf: bool,
+    pub self_mute: bool,
+    pub self_stream: Option<bool>,
+    pub self_video: bool,
+
+    pub request_to_speak_timestamp: Option<Timestamp>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VoiceServerUpdate {
+    pub token: String,
+    pub guild_id: Option<Snowflake>,
+    pub channel_id: Option<Snowflake>, // DM calls
+    pub endpoint: Option<String>, // None while discord allocates a server
+}
+
+/// Everything the voice gateway wants for identifying
+#[derive(Debug, Clone)]
+pub struct VoiceConnectionInfo {
+    pub guild_id: Snowflake,
+    pub channel_id: Snowflake,
+    pub user_id: Snowflake,
+    pub session_id: String,
+    pub endpoint: String,
+    pub token: String,
+}
+
+/// Transport encryption modes we can speak, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionMode {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl EncryptionMode {
+    pub const PREFERRED: [EncryptionMode; 2] = [EncryptionMode::Aes256Gcm, EncryptionMode::XChaCha20Poly1305];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncryptionMode::Aes256Gcm => "aead_aes256_gcm_rtpsize",
+            EncryptionMode::XChaCha20Poly1305 => "aead_xchacha20_poly1305_rtpsize",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::PREFERRED.into_iter().find(|m| m.name() == name)
+    }
+
+    pub fn nonce_size(&self) -> usize {
+        match self {
+            EncryptionMode::Aes256Gcm => 12,
+            EncryptionMode::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct SpeakingFlag: u64 {
+        const MICROPHONE = 1 << 0;
+        const SOUNDSHARE = 1 << 1;
+        const PRIORITY = 1 << 2;
+    }
+}
+wire_flags!(SpeakingFlag);
+
+// Voice gateway READY (op 2)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VoiceReady {
+    pub ssrc: u32,
+    pub ip: String,
+    pub port: u16,
+    pub modes: Vec<String>,
+}
+
+// Voice gateway SESSION_DESCRIPTION (op 4)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SessionDescription {
+    pub mode: String,
+    pub secret_key: Vec<u8>,
+}
@@ -0,0 +1,564 @@
+use crate::{
+    types::voice::{EncryptionMode, SessionDescription, SpeakingFlag, VoiceConnectionInfo, VoiceReady},
+    websocket::{Outgoing, StreamCtrl},
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::XChaCha20Poly1305;
+use reqwest_websocket::{websocket, Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JSON};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+// How long each step of the voice handshake may take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 48kHz, 20ms per opus frame
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+const FRAME_SAMPLES: u32 = 960;
+
+// Opus encoding of silence, sent a few times after speaking so other clients don't interpolate
+pub const SILENCE_FRAME: [u8; 3] = [0xF8, 0xFF, 0xFE];
+const SILENCE_FRAMES: usize = 5;
+
+const RTP_HEADER_SIZE: usize = 12;
+const DISCOVERY_PACKET_SIZE: usize = 74;
+
+/// Voice gateway message (v8)
+///
+/// Same shape as `DiscordMessage` but discord calls the sequence number `seq` here.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VoiceMessage {
+    pub op: u8,
+
+    #[serde(rename = "d")]
+    pub data: JSON,
+
+    #[serde(skip_serializing)]
+    pub seq: Option<u64>,
+}
+
+impl VoiceMessage {
+    pub fn new(op: u8, data: JSON) -> Self {
+        Self { op, data, seq: None }
+    }
+
+    pub fn new_identify(info: &VoiceConnectionInfo) -> Self {
+        Self::new(0, json!({
+            "server_id": info.guild_id,
+            "user_id": info.user_id,
+            "session_id": info.session_id,
+            "token": info.token,
+        }))
+    }
+
+    pub fn new_select_protocol(address: &IpAddr, port: u16, mode: EncryptionMode) -> Self {
+        Self::new(1, json!({
+            "protocol": "udp",
+            "data": {
+                "address": address.to_string(),
+                "port": port,
+                "mode": mode.name(),
+            },
+        }))
+    }
+
+    pub fn new_heartbeat(seq_ack: Option<u64>) -> Self {
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        Self::new(3, json!({ "t": nonce, "seq_ack": seq_ack }))
+    }
+
+    pub fn new_speaking(flags: SpeakingFlag, ssrc: u32) -> Self {
+        Self::new(5, json!({ "speaking": flags, "delay": 0, "ssrc": ssrc }))
+    }
+
+    pub fn parse(msg: Message) -> Result<Self> {
+        Ok(match msg {
+            Message::Text(t) => serde_json::from_str(t.as_str())?,
+            Message::Binary(b) => serde_json::from_slice(b.as_slice())?,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<Message> {
+        Ok(Message::Text(serde_json::to_string(self)?))
+    }
+}
+
+enum Cipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    XChaCha20Poly1305(Box<XChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(mode: EncryptionMode, key: &[u8]) -> Result<Self> {
+        let invalid = |_| anyhow!("Voice server sent an invalid secret key");
+
+        Ok(match mode {
+            EncryptionMode::Aes256Gcm => Self::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(key).map_err(invalid)?)),
+            EncryptionMode::XChaCha20Poly1305 => {
+                Self::XChaCha20Poly1305(Box::new(XChaCha20Poly1305::new_from_slice(key).map_err(invalid)?))
+            }
+        })
+    }
+
+    // Returns ciphertext + tag, `nonce` must be the mode's full nonce size
+    fn encrypt(&self, nonce: &[u8], header: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload { msg: payload, aad: header };
+
+        match self {
+            Self::Aes256Gcm(c) => c.encrypt(nonce.into(), payload),
+            Self::XChaCha20Poly1305(c) => c.encrypt(nonce.into(), payload),
+        }
+        .map_err(|_| anyhow!("Couldn't encrypt voice packet"))
+    }
+}
+
+/// A voice connection that can send opus audio
+///
+/// Created from the info returned by `Websocket::update_voice_state`. The voice websocket
+/// is heartbeated in the background until the connection is closed or dropped.
+pub struct VoiceConnection {
+    tx: mpsc::Sender<Outgoing>,
+    udp: UdpSocket,
+    heartbeat: JoinHandle<()>,
+    cipher: Cipher,
+
+    pub info: VoiceConnectionInfo,
+    pub ssrc: u32,
+    pub mode: EncryptionMode,
+
+    sequence: u16,
+    timestamp: u32,
+    nonce: u32,
+    speaking: bool,
+}
+
+impl VoiceConnection {
+    /// Runs the whole voice handshake: identify, IP discovery, select protocol and session description
+    pub async fn connect(info: VoiceConnectionInfo) -> Result<Self> {
+        let endpoint = info.endpoint.trim_start_matches("wss://");
+        log::debug!("Connecting to voice server {endpoint}");
+
+        let ws = websocket(format!("wss://{endpoint}/?v=8")).await?;
+        Self::handshake(ws, info).await
+    }
+
+    // Everything after opening the websocket
+    async fn handshake(ws: WebSocket, info: VoiceConnectionInfo) -> Result<Self> {
+        let (tx, mut rx) = StreamCtrl::new(ws).start().await;
+        let mut seq_ack = None;
+
+        let hello = Self::expect(&mut rx, 8, &mut seq_ack).await?;
+        let heartbeat = hello.data["heartbeat_interval"]
+            .as_f64()
+            .ok_or(anyhow!("Voice HELLO has no heartbeat interval"))?;
+
+        Self::send_on(&tx, VoiceMessage::new_identify(&info)).await?;
+        let ready: VoiceReady = serde_json::from_value(Self::expect(&mut rx, 2, &mut seq_ack).await?.data)?;
+
+        let mode = EncryptionMode::PREFERRED
+            .into_iter()
+            .find(|m| ready.modes.iter().any(|name| name == m.name()))
+            .ok_or(anyhow!("Voice server supports none of our encryption modes: {:?}", ready.modes))?;
+
+        let udp = UdpSocket::bind("0.0.0.0:0").await?;
+        udp.connect((ready.ip.as_str(), ready.port)).await?;
+        let external = Self::discover_ip(&udp, ready.ssrc).await?;
+        log::debug!("Voice IP discovery says we are {external}");
+
+        Self::send_on(&tx, VoiceMessage::new_select_protocol(&external.ip(), external.port(), mode)).await?;
+        let session: SessionDescription = serde_json::from_value(Self::expect(&mut rx, 4, &mut seq_ack).await?.data)?;
+
+        if EncryptionMode::from_name(&session.mode) != Some(mode) {
+            bail!("Voice server picked encryption mode {} instead of {}", session.mode, mode.name());
+        }
+
+        let cipher = Cipher::new(mode, &session.secret_key)?;
+        let heartbeat = tokio::task::spawn(Self::heartbeat_loop(
+            tx.clone(),
+            rx,
+            Duration::from_secs_f64(heartbeat / 1000.0),
+            seq_ack,
+        ));
+
+        Ok(Self {
+            tx,
+            udp,
+            heartbeat,
+            cipher,
+            info,
+            ssrc: ready.ssrc,
+            mode,
+            sequence: fastrand::u16(..),
+            timestamp: fastrand::u32(..),
+            nonce: 0,
+            speaking: false,
+        })
+    }
+
+    async fn send_on(tx: &mpsc::Sender<Outgoing>, msg: VoiceMessage) -> Result<()> {
+        tx.send(Outgoing::Frame(msg.serialize()?))
+            .await
+            .map_err(|_| anyhow!("Voice websocket write loop has stopped"))
+    }
+
+    // Waits for `op`, skipping anything else the server sends during the handshake
+    async fn expect(rx: &mut mpsc::Receiver<Message>, op: u8, seq_ack: &mut Option<u64>) -> Result<VoiceMessage> {
+        let wait = async {
+            loop {
+                let msg = match rx.recv().await {
+                    Some(m) => m,
+                    None => bail!("Voice websocket closed during handshake"),
+                };
+
+                let msg = match VoiceMessage::parse(msg) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        log::debug!("Skipping unparsable voice message: {e}");
+                        continue;
+                    }
+                };
+
+                if msg.seq.is_some() {
+                    *seq_ack = msg.seq;
+                }
+
+                match msg.op {
+                    o if o == op => break Ok(msg),
+                    255 => bail!("Voice websocket disconnected during handshake"), // see websocket::DISCONNECTED
+                    other => log::trace!("Skipping voice op {other} while waiting for op {op}"),
+                }
+            }
+        };
+
+        timeout(HANDSHAKE_TIMEOUT, wait)
+            .await
+            .map_err(|_| crate::error::Error::Timeout(HANDSHAKE_TIMEOUT))?
+    }
+
+    /// Asks the voice server which address and port our UDP socket appears as
+    async fn discover_ip(udp: &UdpSocket, ssrc: u32) -> Result<SocketAddr> {
+        let mut packet = [0u8; DISCOVERY_PACKET_SIZE];
+        packet[0..2].copy_from_slice(&1u16.to_be_bytes()); // request
+        packet[2..4].copy_from_slice(&70u16.to_be_bytes()); // length, excluding type and length
+        packet[4..8].copy_from_slice(&ssrc.to_be_bytes());
+
+        udp.send(&packet).await?;
+
+        let mut resp = [0u8; DISCOVERY_PACKET_SIZE];
+        let len = timeout(HANDSHAKE_TIMEOUT, udp.recv(&mut resp))
+            .await
+            .map_err(|_| crate::error::Error::Timeout(HANDSHAKE_TIMEOUT))??;
+
+        if len < DISCOVERY_PACKET_SIZE || resp[0..2] != 2u16.to_be_bytes() {
+            bail!("Invalid IP discovery response");
+        }
+
+        let address = &resp[8..72];
+        let address = &address[..address.iter().position(|b| *b == 0).unwrap_or(address.len())];
+        let address: IpAddr = std::str::from_utf8(address)?.parse()?;
+        let port = u16::from_be_bytes([resp[72], resp[73]]);
+
+        Ok(SocketAddr::new(address, port))
+    }
+
+    async fn heartbeat_loop(
+        tx: mpsc::Sender<Outgoing>,
+        mut rx: mpsc::Receiver<Message>,
+        every: Duration,
+        mut seq_ack: Option<u64>,
+    ) {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if Self::send_on(&tx, VoiceMessage::new_heartbeat(seq_ack)).await.is_err() {
+                        break;
+                    }
+                }
+                msg = rx.recv() => {
+                    let msg = match msg.map(VoiceMessage::parse) {
+                        None => break,
+                        Some(Err(e)) => {
+                            log::debug!("Skipping unparsable voice message: {e}");
+                            continue;
+                        }
+                        Some(Ok(m)) => m,
+                    };
+
+                    if msg.seq.is_some() {
+                        seq_ack = msg.seq;
+                    }
+
+                    match msg.op {
+                        6 => log::trace!("Voice heartbeat ACK"),
+                        255 => {
+                            log::warn!("Voice websocket disconnected");
+                            break;
+                        }
+                        op => log::trace!("Ignoring voice op {op}"),
+                    }
+                }
+            }
+        }
+
+        log::trace!("Voice heartbeat loop stopped");
+    }
+
+    pub async fn send(&self, msg: VoiceMessage) -> Result<()> {
+        Self::send_on(&self.tx, msg).await
+    }
+
+    /// Sets our speaking state, no flags means we stopped
+    pub async fn speaking(&mut self, flags: SpeakingFlag) -> Result<()> {
+        self.send(VoiceMessage::new_speaking(flags, self.ssrc)).await?;
+        self.speaking = !flags.is_empty();
+        Ok(())
+    }
+
+    /// Builds an encrypted RTP packet around `frame` and advances sequence, timestamp and nonce
+    pub fn packetize(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        let mut header = [0u8; RTP_HEADER_SIZE];
+        header[0] = 0x80; // version 2, no padding/extension/csrc
+        header[1] = 0x78; // payload type 120
+        header[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        header[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        header[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+
+        // The counter goes at the start of the nonce, the rest stays zero
+        let counter = self.nonce.to_be_bytes();
+        let mut nonce = vec![0u8; self.mode.nonce_size()];
+        nonce[..4].copy_from_slice(&counter);
+
+        let encrypted = self.cipher.encrypt(&nonce, &header, frame)?;
+
+        let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + encrypted.len() + counter.len());
+        packet.extend_from_slice(&header);
+        packet.extend_from_slice(&encrypted);
+        packet.extend_from_slice(&counter);
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(FRAME_SAMPLES);
+        self.nonce = self.nonce.wrapping_add(1);
+
+        Ok(packet)
+    }
+
+    /// Sends a single 20ms opus frame right away, pacing is up to the caller
+    pub async fn send_opus(&mut self, frame: &[u8]) -> Result<()> {
+        if !self.speaking {
+            self.speaking(SpeakingFlag::MICROPHONE).await?;
+        }
+
+        let packet = self.packetize(frame)?;
+        self.udp.send(&packet).await?;
+
+        Ok(())
+    }
+
+    /// Plays opus frames at 20ms intervals, then stops speaking
+    pub async fn play<I>(&mut self, frames: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut ticker = interval(FRAME_DURATION);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+        for frame in frames {
+            ticker.tick().await;
+            self.send_opus(frame.as_ref()).await?;
+        }
+
+        self.stop().await
+    }
+
+    /// Sends trailing silence frames and clears our speaking state
+    pub async fn stop(&mut self) -> Result<()> {
+        if !self.speaking {
+            return Ok(());
+        }
+
+        let mut ticker = interval(FRAME_DURATION);
+        for _ in 0..SILENCE_FRAMES {
+            ticker.tick().await;
+            let packet = self.packetize(&SILENCE_FRAME)?;
+            self.udp.send(&packet).await?;
+        }
+
+        self.speaking(SpeakingFlag::empty()).await
+    }
+
+    /// Stops speaking and closes the voice websocket
+    ///
+    /// This doesn't leave the channel, use `Websocket::update_voice_state` with no channel for that.
+    pub async fn close(mut self) -> Result<()> {
+        self.stop().await?;
+        self.heartbeat.abort();
+
+        let (done, wait) = tokio::sync::oneshot::channel();
+        if self.tx.send(Outgoing::Close(done)).await.is_ok() {
+            let _ = wait.await;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for VoiceConnection {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage, WebSocketStream};
+
+    const SSRC: u32 = 42;
+    const KEY: [u8; 32] = [7; 32];
+
+    type ServerWs = WebSocketStream<tokio::net::TcpStream>;
+
+    async fn send(ws: &mut ServerWs, op: u8, data: JSON) {
+        let msg = json!({ "op": op, "d": data }).to_string();
+        ws.send(WsMessage::Text(msg)).await.unwrap();
+    }
+
+    // Skips heartbeats and anything else until `op` comes in
+    async fn recv(ws: &mut ServerWs, op: u8) -> JSON {
+        loop {
+            let msg = ws.next().await.unwrap().unwrap();
+            let msg: JSON = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["op"] == op {
+                return msg["d"].clone();
+            }
+        }
+    }
+
+    // Plays the voice server's side of the handshake, then returns the first audio packet
+    async fn fake_server(listener: TcpListener, udp: UdpSocket) -> (JSON, Vec<u8>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+
+        send(&mut ws, 8, json!({ "heartbeat_interval": 41250.0 })).await;
+
+        let identify = recv(&mut ws, 0).await;
+        assert_eq!(identify["server_id"], "1");
+        assert_eq!(identify["user_id"], "3");
+        assert_eq!(identify["session_id"], "session");
+        assert_eq!(identify["token"], "token");
+
+        let port = udp.local_addr().unwrap().port();
+        let modes = ["xsalsa20_poly1305", "aead_aes256_gcm_rtpsize"];
+        send(&mut ws, 2, json!({ "ssrc": SSRC, "ip": "127.0.0.1", "port": port, "modes": modes })).await;
+
+        // IP discovery, answered with the address the request came from
+        let mut request = [0u8; DISCOVERY_PACKET_SIZE];
+        let (len, from) = udp.recv_from(&mut request).await.unwrap();
+        assert_eq!(len, DISCOVERY_PACKET_SIZE);
+        assert_eq!(request[0..2], 1u16.to_be_bytes());
+        assert_eq!(request[4..8], SSRC.to_be_bytes());
+
+        let mut response = [0u8; DISCOVERY_PACKET_SIZE];
+        response[0..2].copy_from_slice(&2u16.to_be_bytes());
+        response[2..4].copy_from_slice(&70u16.to_be_bytes());
+        response[4..8].copy_from_slice(&SSRC.to_be_bytes());
+        let address = from.ip().to_string();
+        response[8..8 + address.len()].copy_from_slice(address.as_bytes());
+        response[72..74].copy_from_slice(&from.port().to_be_bytes());
+        udp.send_to(&response, from).await.unwrap();
+
+        let select = recv(&mut ws, 1).await;
+        assert_eq!(select["protocol"], "udp");
+        assert_eq!(select["data"]["address"], address);
+        assert_eq!(select["data"]["port"], from.port());
+        assert_eq!(select["data"]["mode"], "aead_aes256_gcm_rtpsize");
+
+        send(&mut ws, 4, json!({ "mode": "aead_aes256_gcm_rtpsize", "secret_key": KEY })).await;
+
+        let speaking = recv(&mut ws, 5).await;
+        let mut packet = vec![0u8; 1500];
+        let len = udp.recv(&mut packet).await.unwrap();
+        packet.truncate(len);
+
+        // Keep the websocket open until the client closes it
+        while let Some(Ok(msg)) = ws.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+
+        (speaking, packet)
+    }
+
+    #[tokio::test]
+    async fn handshake_and_send_audio() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::task::spawn(fake_server(listener, udp));
+
+        let info = VoiceConnectionInfo {
+            guild_id: 1.into(),
+            channel_id: 2.into(),
+            user_id: 3.into(),
+            session_id: "session".to_string(),
+            endpoint: url.clone(),
+            token: "token".to_string(),
+        };
+
+        let ws = websocket(url).await.unwrap();
+        let mut voice = VoiceConnection::handshake(ws, info).await.unwrap();
+        assert_eq!(voice.ssrc, SSRC);
+        assert_eq!(voice.mode, EncryptionMode::Aes256Gcm);
+
+        let (sequence, timestamp) = (voice.sequence, voice.timestamp);
+        let frame = [1, 2, 3, 4, 5];
+        voice.send_opus(&frame).await.unwrap();
+        drop(voice);
+
+        let (speaking, packet) = server.await.unwrap();
+        assert_eq!(speaking["speaking"], SpeakingFlag::MICROPHONE.bits());
+        assert_eq!(speaking["ssrc"], SSRC);
+
+        let (header, rest) = packet.split_at(RTP_HEADER_SIZE);
+        assert_eq!(header[0..2], [0x80, 0x78]);
+        assert_eq!(header[2..4], sequence.to_be_bytes());
+        assert_eq!(header[4..8], timestamp.to_be_bytes());
+        assert_eq!(header[8..12], SSRC.to_be_bytes());
+
+        // The 4 byte nonce counter trails the packet and is zero-padded to the full nonce
+        let (encrypted, counter) = rest.split_at(rest.len() - 4);
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(counter);
+
+        let cipher = Aes256Gcm::new_from_slice(&KEY).unwrap();
+        let decrypted = cipher
+            .decrypt(&nonce.into(), Payload { msg: encrypted, aad: header })
+            .unwrap();
+        assert_eq!(decrypted, frame);
+    }
+}
@@ -1,19 +1,42 @@
-use crate::{context::ResumeInfo, prelude::{Arc, Ctx, Mutex}};
+use crate::{
+    context::{Context, ResumeInfo},
+    error::Error,
+    prelude::{Arc, Ctx, Mutex},
+    types::{
+        gateway::{GuildMembers, GuildMembersChunk, GuildMembersRequest, GuildSubscription, MemberQuery},
+        presence::GatewayPresence,
+        voice::{VoiceConnectionInfo, VoiceServerUpdate, VoiceState},
+        Snowflake,
+    },
+};
 
 use anyhow::Result;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
 use reqwest_websocket::{websocket, Message, WebSocket as WS};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JSON};
-use std::{collections::VecDeque, time::Duration};
+use std::{collections::HashSet, future::Future, time::Duration};
+use tokio::sync::{
+    mpsc::{self, error::TryRecvError},
+    oneshot,
+};
 
 static DISCORD_WS_URI: &str = "wss://gateway.discord.gg/?encoding=json&v=9";
 
+// Pseudo-opcode pushed into the incoming queue when the connection drops
+static DISCONNECTED: &str = r#"{"op":255}"#;
+
+// How long to wait for discord to hand out a voice server
+const VOICE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Max number of messages buffered in either direction before senders have to wait
+const QUEUE_SIZE: usize = 64;
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DiscordMessage {
     pub op: u8,
 
-    #[serde(rename = "d")]
+    #[serde(rename = "d", default)] // missing in our own DISCONNECTED message
     pub data: JSON,
 
     #[serde(rename = "s", skip_serializing)]
@@ -36,6 +59,79 @@ impl DiscordMessage {
         }
     }
 
+    pub fn new_identify_with(token: &str, presence: Option<&GatewayPresence>) -> Self {
+        let mut msg = Self::new_identify(token);
+        if let Some(presence) = presence {
+            msg.data["presence"] = json!(presence);
+        }
+        msg
+    }
+
+    pub fn new_request_guild_members(request: &GuildMembersRequest) -> Self {
+        Self {
+            op: 8,
+            data: request.build(),
+            seq: None,
+            event: None,
+        }
+    }
+
+    pub fn new_lazy_request(subscription: &GuildSubscription) -> Self {
+        let mut data = subscription.build();
+        data["guild_id"] = json!(subscription.guild_id);
+
+        Self {
+            op: 14,
+            data,
+            seq: None,
+            event: None,
+        }
+    }
+
+    pub fn new_guild_subscriptions(subscriptions: &[GuildSubscription]) -> Self {
+        let subscriptions: serde_json::Map<String, JSON> = subscriptions
+            .iter()
+            .map(|s| (s.guild_id.to_string(), s.build()))
+            .collect();
+
+        Self {
+            op: 37,
+            data: json!({ "subscriptions": subscriptions }),
+            seq: None,
+            event: None,
+        }
+    }
+
+    pub fn new_voice_state_update(
+        guild_id: Snowflake,
+        channel_id: Option<Snowflake>,
+        self_mute: bool,
+        self_deaf: bool,
+        self_video: bool,
+    ) -> Self {
+        Self {
+            op: 4,
+            data: json!({
+                "guild_id": guild_id,
+                "channel_id": channel_id,
+                "self_mute": self_mute,
+                "self_deaf": self_deaf,
+                "self_video": self_video,
+            }),
+            seq: None,
+            event: None,
+        }
+    }
+
+    pub fn new_presence_update(presence: &GatewayPresence) -> Self {
+        Self {
+            op: 3,
+            data: json!(presence),
+            seq: None,
+            event: None,
+        }
+    }
+
     pub fn new_identify(token: &str) -> Self {
         // Yeah the formatting is strange but it's to provide easy access
         // to "token" and "client_build_number" without searching or scrolling
@@ -64,27 +160,153 @@ impl DiscordMessage {
     }
 }
 
+/// Connection events generated by hubbub rather than sent by discord
+///
+/// These reach the handler like any other dispatch, `Lifecycle::from_message` turns them back
+/// into this enum. `Resumed` is discord's own RESUMED event.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Lifecycle {
+    Disconnected,
+    Reconnecting { attempt: u32 },
+    Resumed,
+}
+
+impl Lifecycle {
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            Lifecycle::Disconnected => "DISCONNECTED",
+            Lifecycle::Reconnecting { .. } => "RECONNECTING",
+            Lifecycle::Resumed => "RESUMED",
+        }
+    }
+
+    pub fn from_message(msg: &DiscordMessage) -> Option<Self> {
+        match msg.event.as_deref()? {
+            "DISCONNECTED" => Some(Lifecycle::Disconnected),
+            "RECONNECTING" => Some(Lifecycle::Reconnecting {
+                attempt: msg.data["attempt"].as_u64()? as u32,
+            }),
+            "RESUMED" => Some(Lifecycle::Resumed),
+            _ => None,
+        }
+    }
+}
+
+impl From<Lifecycle> for DiscordMessage {
+    fn from(value: Lifecycle) -> Self {
+        Self {
+            op: 0,
+            data: match value {
+                Lifecycle::Reconnecting { attempt } => json!({ "attempt": attempt }),
+                _ => JSON::Null,
+            },
+            seq: None,
+            event: Some(value.event_name().to_string()),
+        }
+    }
+}
+
+/// How long to wait between connection attempts and when to give up
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`; with jitter enabled,
+/// each delay is randomized between half and the full value.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: Option<u32>, // None = never give up
+    pub jitter: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: Some(10),
+            jitter: true,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            base_delay,
+            max_delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn max_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn gives_up_after(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+
+    // attempt starts at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(exp).min(self.max_delay);
+
+        if self.jitter {
+            delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+        } else {
+            delay
+        }
+    }
+
+    /// Retries `f` according to this policy, returning the first success
+    ///
+    /// `f` gets the attempt number, starting at 1.
+    pub async fn retry<T>(&self, mut f: impl AsyncFnMut(u32) -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match f(attempt).await {
+                Ok(v) => break Ok(v),
+                Err(e) if self.gives_up_after(attempt + 1) => {
+                    break Err(e.context(Error::ReconnectFailed(attempt)))
+                }
+                Err(e) => {
+                    let delay = self.delay(attempt);
+                    log::info!("Attempt #{attempt} failed ({e}), retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+pub enum Outgoing {
+    Frame(Message),
+
+    // Flushes everything queued before it, sends a close frame and then notifies the sender
+    Close(oneshot::Sender<()>),
+}
+
 pub struct StreamCtrl {
     s: WS,
-    rx: VecDeque<Message>,
-    tx: VecDeque<Message>,
 }
 
 impl StreamCtrl {
     pub fn new(s: WS) -> Self {
-        Self {
-            s,
-            rx: VecDeque::with_capacity(64),
-            tx: VecDeque::with_capacity(64),
-        }
+        Self { s }
     }
 
-    pub async fn start(self) -> (Arc<Mutex<VecDeque<Message>>>, Arc<Mutex<VecDeque<Message>>>) {
-        let _txq = Arc::new(Mutex::new(self.tx));
-        let _rxq = Arc::new(Mutex::new(self.rx));
+    pub async fn start(self) -> (mpsc::Sender<Outgoing>, mpsc::Receiver<Message>) {
+        let (txq, mut txq_rx) = mpsc::channel::<Outgoing>(QUEUE_SIZE);
+        let (rxq_tx, rxq) = mpsc::channel::<Message>(QUEUE_SIZE);
         let (mut tx, mut rx) = self.s.into_stream().split();
 
-        let rxq = _rxq.clone();
         tokio::task::spawn(async move {
             log::trace!("Starting websocket read loop");
 
@@ -92,17 +314,13 @@ impl StreamCtrl {
                 let msg = match rx.next().await {
                     Some(r) => match r {
                         Err(e) => {
-                            let mut rxq = rxq.lock().await;
-                            rxq.close().await.expect("Couldn't close sink");
-                            rxq.push_back(Message::Text(String::from(r#"{"op":255}"#)));
                             log::error!("rxq: {e}");
+                            let _ = rxq_tx.send(Message::Text(String::from(DISCONNECTED))).await;
                             return;
                         }
                         Ok(m) => m,
                     },
                     None => {
-                        let mut rxq = rxq.lock().await;
-                        rxq.close().await.expect("Couldn't close sink");
                         log::trace!("rxq closed because end-of-stream reached");
                         return;
                     }
@@ -110,66 +328,118 @@ impl StreamCtrl {
 
                 log::trace!("<<\n{msg:?}");
 
-                let mut rxq = rxq.lock().await;
-                rxq.push_back(msg);
+                // Waits for room in the queue, so a slow consumer stops us from reading the socket
+                if rxq_tx.send(msg).await.is_err() {
+                    log::trace!("rxq closed because receiver was dropped");
+                    return;
+                }
             }
         });
 
-        let txq = _txq.clone();
         tokio::task::spawn(async move {
             log::trace!("Starting websocket write loop");
 
-            loop {
-                tokio::time::sleep(Duration::from_millis(5)).await;
-                
-                let mut txq = txq.lock().await;
-                if txq.is_empty() { continue }
-
-                while let Some(msg) = txq.pop_front() {
-                    log::trace!(">>\n{msg:?}");
-                    tx.feed(msg).await.expect("Couldn't feed tx");
+            let mut closing = None;
+            while let Some(out) = txq_rx.recv().await {
+                let mut res = match out {
+                    Outgoing::Frame(msg) => {
+                        log::trace!(">>\n{msg:?}");
+                        tx.feed(msg).await
+                    }
+                    Outgoing::Close(done) => {
+                        closing = Some(done);
+                        break;
+                    }
+                };
+
+                // Batch up anything else that was queued in the meantime
+                while res.is_ok() {
+                    match txq_rx.try_recv() {
+                        Ok(Outgoing::Frame(msg)) => {
+                            log::trace!(">>\n{msg:?}");
+                            res = tx.feed(msg).await;
+                        }
+                        Ok(Outgoing::Close(done)) => {
+                            closing = Some(done);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+
+                if res.is_ok() {
+                    res = tx.flush().await;
                 }
 
-                if let Err(e) = tx.flush().await {
-                    txq.close().await.expect("Couldn't close stream");
-                    log::error!("{e:?}");
+                if let Err(e) = res {
+                    log::error!("txq: {e:?}");
                     return;
                 }
 
-                drop(txq);
+                if closing.is_some() {
+                    break;
+                }
+            }
+
+            // Either we were asked to close or the websocket was dropped, say goodbye properly
+            log::trace!("Closing websocket write loop");
+            if let Err(e) = tx.close().await {
+                log::debug!("Couldn't send close frame: {e:?}");
+            }
+
+            if let Some(done) = closing {
+                let _ = done.send(());
             }
         });
 
-        (_txq, _rxq)
+        (txq, rxq)
     }
 }
 
-type Queue = Arc<Mutex<VecDeque<Message>>>;
-
 pub struct Websocket {
-    // tx, rx
-    pub q: (Queue, Queue),
+    tx: mpsc::Sender<Outgoing>,
+    rx: Reader,
 
-    pub ready: bool,
+    pub ready: bool, // identify or resume was sent, so other gateway commands can follow
 
     pub heartbeat: u64,
     pub sequence: Option<u64>,
 }
 
+/// Shared handle to the incoming side of a [`Websocket`]
+///
+/// Reading through this doesn't need a lock on the websocket itself,
+/// so the client can wait for messages while handlers are still free to send.
+#[derive(Clone)]
+pub struct Reader(Arc<Mutex<mpsc::Receiver<Message>>>);
+
+impl Reader {
+    pub async fn read(&self) -> Result<DiscordMessage> {
+        Websocket::parse_message(match self.0.lock().await.recv().await {
+            Some(m) => m,
+            None => Message::Text(String::from(DISCONNECTED)),
+        })
+    }
+
+    pub async fn try_read(&self) -> Result<Option<DiscordMessage>> {
+        Ok(match self.0.lock().await.try_recv() {
+            Ok(m) => Some(Websocket::parse_message(m)?),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Websocket::parse_message(Message::Text(String::from(DISCONNECTED)))?)
+            }
+        })
+    }
+}
+
 impl Websocket {
+    // Single attempt, retrying is up to the caller's ReconnectPolicy
     async fn connect(url: String) -> Result<WS> {
-        Ok(loop {
-            log::debug!("Trying websocket connection");
-            let attempt = websocket(url.clone()).await;
+        log::debug!("Trying websocket connection");
 
-            match attempt {
-                Ok(v) => { break v },
-                Err(e) => {
-                    log::error!("Got error while trying to connect: {e}");
-                    log::info!("Retrying in 5s");
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
-            }
+        websocket(url).await.map_err(|e| {
+            log::error!("Got error while trying to connect: {e}");
+            e.into()
         })
     }
 
@@ -184,7 +454,8 @@ impl Websocket {
             ready: false,
             heartbeat: 0,
             sequence: None,
-            q: (tx, rx),
+            tx,
+            rx: Reader(Arc::new(Mutex::new(rx))),
         })
     }
 
@@ -200,47 +471,49 @@ impl Websocket {
             ready: false,
             heartbeat: 0,
             sequence: Some(sequence),
-            q: (tx, rx),
+            tx,
+            rx: Reader(Arc::new(Mutex::new(rx))),
         })
     }
 
+    pub fn reader(&self) -> Reader {
+        self.rx.clone()
+    }
+
     pub async fn send(&mut self, msg: DiscordMessage) -> Result<()> {
         let msg = Self::serialize_message(msg)?;
 
-        let mut lock = self.q.0.lock().await;
-        lock.push_back(msg);
+        self.tx.send(Outgoing::Frame(msg)).await
+            .map_err(|_| anyhow::anyhow!("Websocket write loop has stopped"))
+    }
+
+    /// Sends a close frame once everything already queued has been written
+    pub async fn close(&mut self) -> Result<()> {
+        let (done, wait) = oneshot::channel();
+
+        // If the write loop is already gone there's nothing left to close
+        if self.tx.send(Outgoing::Close(done)).await.is_ok() {
+            let _ = wait.await;
+        }
 
         Ok(())
     }
 
     pub async fn read(&mut self) -> Result<DiscordMessage> {
-        loop {
-            let mut lock = self.q.1.lock().await;
-
-            if lock.is_empty() {
-                drop(lock);
-                tokio::time::sleep(Duration::from_millis(5)).await;
-                continue;
-            }
-
-            break Ok(Self::parse_message(lock.pop_front().unwrap())?);
-        }
+        self.rx.read().await
     }
 
     pub async fn try_read(&mut self) -> Result<Option<DiscordMessage>> {
-        let mut lock = self.q.1.lock().await;
-
-        Ok(match lock.pop_front() {
-            Some(v) => Some(Self::parse_message(v)?),
-            None => None,
-        })
+        self.rx.try_read().await
     }
 
     pub fn parse_message(msg: Message) -> Result<DiscordMessage> {
-        Ok(match msg {
-            Message::Text(t) => serde_json::from_str(t.as_str())?,
-            Message::Binary(b) => serde_json::from_slice(b.as_slice())?,
-        })
+        let parsed = match msg {
+            Message::Text(t) => serde_json::from_str(t.as_str()),
+            Message::Binary(b) => serde_json::from_slice(b.as_slice()),
+        };
+
+        Ok(parsed.map_err(|e| Error::MalformedPayload(e.to_string()))?)
     }
 
     pub fn serialize_message(msg: DiscordMessage) -> Result<Message> {
@@ -249,9 +522,10 @@ impl Websocket {
 
     async fn read_hello(&mut self) -> Result<()> {
         let hello = self.read().await?;
-        self.heartbeat = hello.data["heartbeat_interval"]
-            .as_u64()
-            .expect("Invalid heartbeat interval");
+        self.heartbeat = match (hello.op, hello.data["heartbeat_interval"].as_u64()) {
+            (10, Some(interval)) if interval > 0 => interval,
+            (op, _) => return Err(Error::MalformedPayload(format!("expected HELLO, got op {op}")).into()),
+        };
 
         self.send(DiscordMessage::new_heartbeat(None)).await?;
         let _ = self.read().await?;
@@ -260,23 +534,296 @@ impl Websocket {
     }
 
     pub async fn login(&mut self, token: &str) -> Result<()> {
+        self.login_with(token, None).await
+    }
+
+    // Same as login, but comes online with `presence` instead of discord's default
+    pub async fn login_with(&mut self, token: &str, presence: Option<&GatewayPresence>) -> Result<()> {
         self.read_hello().await?;
+        self.identify(token, presence).await
+    }
+
+    // Identify on a connection that already got past HELLO, e.g. after an invalid session
+    pub async fn identify(&mut self, token: &str, presence: Option<&GatewayPresence>) -> Result<()> {
+        if let Some(presence) = presence {
+            presence.validate()?;
+        }
 
         log::debug!("Sending identify packet");
-        self.send(DiscordMessage::new_identify(token)).await?;
+        self.send(DiscordMessage::new_identify_with(token, presence)).await?;
+        self.ready = true;
+
+        Ok(())
+    }
+
+    /// Asks for members of a guild over the gateway (op 8)
+    ///
+    /// Once sent, the returned future collects every GUILD_MEMBERS_CHUNK with the request's nonce,
+    /// adds the members to the cache and resolves, or fails after the request's timeout.
+    /// Chunks only come in while `Client::run` is running. The future locks `ctx` when done, so
+    /// don't hold the websocket while awaiting it.
+    pub async fn request_guild_members(
+        &mut self,
+        ctx: Ctx,
+        request: GuildMembersRequest,
+    ) -> Result<impl Future<Output = Result<GuildMembers>> + Send> {
+        if let MemberQuery::UserIds(ids) = &request.query {
+            if ids.is_empty() || ids.len() > 100 {
+                return Err(Error::InvalidApiRequest("user_ids must have 1 to 100 ids".to_string()).into());
+            }
+        }
+        if request.nonce.len() > 32 {
+            return Err(Error::InvalidApiRequest("nonce must be at most 32 bytes".to_string()).into());
+        }
+
+        let nonce = request.nonce.clone();
+        let mut chunks = ctx.listeners().listen(move |msg| {
+            msg.event.as_deref() == Some("GUILD_MEMBERS_CHUNK")
+                && msg.data["nonce"].as_str() == Some(nonce.as_str())
+        });
+
+        log::debug!("Requesting members of guild {}", request.guild_id);
+        self.send(DiscordMessage::new_request_guild_members(&request)).await?;
+
+        Ok(async move {
+            let mut result = GuildMembers::default();
+            let mut seen = HashSet::new();
+
+            let collect = async {
+                while let Some(msg) = chunks.recv().await {
+                    let chunk: GuildMembersChunk = serde_json::from_value(msg.data)?;
+                    seen.insert(chunk.chunk_index);
+
+                    result.members.extend(chunk.members);
+                    result.not_found.extend(chunk.not_found);
+                    result.presences.extend(chunk.presences);
+
+                    if seen.len() as u64 >= chunk.chunk_count {
+                        break;
+                    }
+                }
+                anyhow::Ok(())
+            };
+
+            tokio::time::timeout(request.timeout, collect)
+                .await
+                .map_err(|_| Error::Timeout(request.timeout))??;
+
+            ctx.lock().await.cache.add_members(request.guild_id, &result.members);
+            Ok(result)
+        })
+    }
+
+    /// Subscribes to member-list ranges, typing and activities for any number of guilds (op 37)
+    ///
+    /// Member lists show up in the cache as GUILD_MEMBER_LIST_UPDATE events come in.
+    pub async fn subscribe_guilds(&mut self, subscriptions: Vec<GuildSubscription>) -> Result<()> {
+        for s in subscriptions.iter() {
+            s.validate()?;
+        }
+
+        log::debug!("Updating subscriptions for {} guilds", subscriptions.len());
+        self.send(DiscordMessage::new_guild_subscriptions(&subscriptions)).await
+    }
+
+    // Older single-guild version of subscribe_guilds (op 14)
+    pub async fn lazy_request(&mut self, subscription: GuildSubscription) -> Result<()> {
+        subscription.validate()?;
+
+        log::debug!("Sending lazy request for guild {}", subscription.guild_id);
+        self.send(DiscordMessage::new_lazy_request(&subscription)).await
+    }
+
+    /// Joins, moves between or leaves (`channel_id: None`) voice channels (op 4)
+    ///
+    /// The returned future waits for our VOICE_STATE_UPDATE and a VOICE_SERVER_UPDATE sent after
+    /// this request, and resolves with what's needed to connect to the voice server, or `None`
+    /// after leaving. Discord hands out a new server token on every join or move.
+    /// Like `request_guild_members`, it needs `Client::run` to be running and locks `ctx`.
+    pub async fn update_voice_state(
+        &mut self,
+        ctx: Ctx,
+        guild_id: Snowflake,
+        channel_id: Option<Snowflake>,
+        self_mute: bool,
+        self_deaf: bool,
+        self_video: bool,
+    ) -> Result<impl Future<Output = Result<Option<VoiceConnectionInfo>>> + Send> {
+        if !self.ready {
+            return Err(Error::NotLoggedIn.into());
+        }
+
+        // Listening before sending, so only updates caused by this request are seen. Our user
+        // id is checked once the future runs, as the context isn't locked while we hold `self`
+        let mut events = ctx.listeners().listen(move |msg| {
+            let guild = msg.data["guild_id"].as_str() == Some(guild_id.to_string().as_str());
+            matches!(msg.event.as_deref(), Some("VOICE_STATE_UPDATE" | "VOICE_SERVER_UPDATE")) && guild
+        });
+
+        log::debug!("Updating voice state in guild {guild_id}: {channel_id:?}");
+        self.send(DiscordMessage::new_voice_state_update(
+            guild_id, channel_id, self_mute, self_deaf, self_video,
+        ))
+        .await?;
+
+        Ok(async move {
+            let user_id = ctx.lock().await.user.as_ref().ok_or(Error::NotLoggedIn)?.id;
+            let mut state: Option<VoiceState> = None;
+            let mut server: Option<VoiceServerUpdate> = None;
+
+            let collect = async {
+                while let Some(msg) = events.recv().await {
+                    match msg.event.as_deref() {
+                        Some("VOICE_STATE_UPDATE") => {
+                            let update: VoiceState = serde_json::from_value(msg.data)?;
+                            if update.user_id != user_id {
+                                continue;
+                            }
+                            match (update.channel_id, channel_id) {
+                                (None, None) => return Ok(None),
+                                (None, Some(_)) => anyhow::bail!("Got disconnected from voice while joining"),
+                                _ => state = Some(update),
+                            }
+                        }
+                        // A server update without an endpoint means another one is coming
+                        _ => {
+                            let update: VoiceServerUpdate = serde_json::from_value(msg.data)?;
+                            if update.endpoint.is_some() {
+                                server = Some(update);
+                            }
+                        }
+                    }
+
+                    if let (Some(state), Some(server)) = (&state, &server) {
+                        if let (Some(channel_id), Some(endpoint)) = (state.channel_id, &server.endpoint) {
+                            return Ok(Some(VoiceConnectionInfo {
+                                guild_id,
+                                channel_id,
+                                user_id,
+                                session_id: state.session_id.clone(),
+                                endpoint: endpoint.clone(),
+                                token: server.token.clone(),
+                            }));
+                        }
+                    }
+                }
+                anyhow::Ok(None)
+            };
+
+            let info = tokio::time::timeout(VOICE_TIMEOUT, collect)
+                .await
+                .map_err(|_| Error::Timeout(VOICE_TIMEOUT))??;
+
+            let mut ctx = ctx.lock().await;
+            match &info {
+                Some(info) => ctx.cache.voice.insert(guild_id, info.clone()),
+                None => ctx.cache.voice.remove(&guild_id),
+            };
+
+            Ok(info)
+        })
+    }
+
+    /// Updates our presence (op 3)
+    ///
+    /// The presence is also kept in `ctx.presence`, so identifying again restores it
+    /// instead of whatever we logged in with.
+    pub async fn update_presence(&mut self, ctx: &mut Context, presence: GatewayPresence) -> Result<()> {
+        presence.validate()?;
+
+        log::debug!("Sending presence update: {}", presence.status);
+        self.send(DiscordMessage::new_presence_update(&presence)).await?;
+        ctx.presence = Some(presence);
 
         Ok(())
     }
 
-    pub async fn resume(&mut self, ctx: Ctx) -> Result<()> {
+    /// Resumes the session in `info` from `self.sequence` on this connection
+    pub async fn resume(&mut self, token: &str, info: &ResumeInfo) -> Result<()> {
+        let Some(seq) = self.sequence else {
+            return Err(Error::NoSession.into());
+        };
+
         self.read_hello().await?;
 
         log::debug!("Sending resume packet");
-
-        let ctx = ctx.lock().await;
-        let info = ctx.resume_info.as_ref().unwrap();
-        self.send(DiscordMessage::new_resume(ctx.auth.as_ref().unwrap(), self.sequence.unwrap(), info)).await?;
+        self.send(DiscordMessage::new_resume(token, seq, info)).await?;
+        self.ready = true;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(jitter: bool) -> ReconnectPolicy {
+        ReconnectPolicy::new(Duration::from_secs(1), Duration::from_secs(10)).jitter(jitter)
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy = policy(false);
+        let delays: Vec<u64> = (1..=6).map(|a| policy.delay(a).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+
+        // Shouldn't overflow no matter how long it keeps trying
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn jitter_stays_between_half_and_full_delay() {
+        let exact = policy(false);
+        let jittered = policy(true);
+
+        for attempt in 1..=8 {
+            let full = exact.delay(attempt);
+            for _ in 0..100 {
+                let delay = jittered.delay(attempt);
+                assert!(delay >= full / 2 && delay <= full, "attempt {attempt}: {delay:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let policy = policy(false).max_attempts(Some(3));
+        assert!(!policy.gives_up_after(3));
+        assert!(policy.gives_up_after(4));
+
+        let forever = policy.max_attempts(None);
+        assert!(!forever.gives_up_after(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn retry_stops_after_max_attempts() {
+        let policy = ReconnectPolicy::new(Duration::from_millis(1), Duration::from_millis(1))
+            .max_attempts(Some(3));
+        let calls = AtomicU32::new(0);
+
+        let result: Result<()> = policy
+            .retry(async |attempt| {
+                assert_eq!(calls.fetch_add(1, Ordering::SeqCst) + 1, attempt);
+                anyhow::bail!("connection refused")
+            })
+            .await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        let err = result.unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ReconnectFailed(3))));
+    }
+
+    #[tokio::test]
+    async fn retry_returns_first_success() {
+        let policy = ReconnectPolicy::new(Duration::from_millis(1), Duration::from_millis(1))
+            .max_attempts(Some(5));
+
+        let result = policy
+            .retry(async |attempt| if attempt < 3 { anyhow::bail!("not yet") } else { Ok(attempt) })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+}